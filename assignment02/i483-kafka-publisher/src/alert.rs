@@ -0,0 +1,104 @@
+/*
+    This is the alert module. It publishes the state changes of the processes to MQTT, retained with QoS 1,
+    so the ESP32 nodes can subscribe to the alerts of their entity (e.g. light the LED of blink_led when CO2 is high).
+    * States: threshold (yes/no), watchdog and entity-watchdog (online/offline), forecast (the crossed alert, yes/no)
+      and consistency (ok/drift/stale:<SENSOR>). The other results are not states, and stay on Kafka only.
+    * Topic: i483/alerts/<ENTITY>/<OUTPUT TOPIC>, e.g. i483/alerts/s2420010/i483-sensors-s2420010-SCD41-co2_threshold-crossed,
+      so a node subscribes to i483/alerts/<ENTITY>/#. The payload is the raw payload of the output topic.
+    * Only a change of the state is published. The retained message keeps the latest state for the nodes which connect later.
+*/
+use std::collections::HashMap;
+use rumqttc::v5::{AsyncClient, Event};
+use rumqttc::v5::mqttbytes::QoS;
+use rumqttc::v5::mqttbytes::v5::Packet;
+use tokio::sync::Mutex;
+use i483_sensors::SensorTopic;
+use crate::cli::ProcessType;
+use crate::mqtt::{self, Reconnect};
+use crate::worker::ProcessData;
+
+pub const ALERT_KINDS: [&str; 5] = ["threshold", "watchdog", "entity-watchdog", "forecast", "consistency"];
+
+
+/// The MQTT topic of the alert of an output topic.
+pub fn alert_topic(output_topic: &str, source_topic: &str) -> String {
+    match SensorTopic::parse(source_topic) {
+        Some(topic) => format!("i483/alerts/{}/{}", topic.entity, output_topic),
+        None => format!("i483/alerts/{}", output_topic),
+    }
+}
+
+pub struct AlertSink {
+    client: AsyncClient,
+    kinds: Vec<String>, // The kinds of the processes whose states are published.
+    states: Mutex<HashMap<String, String>>, // The latest published state of every alert topic.
+}
+
+impl AlertSink {
+    /// Connects to the broker. The event loop is polled by its own task, which reconnects after an error.
+    pub fn start(broker: &(String, u16), client_id: &str, kinds: &[String]) -> AlertSink {
+        let (client, mut event_loop) = mqtt::connect(broker, client_id, 0, false);
+        tokio::spawn(async move {
+            let mut reconnect = Reconnect::new();
+            loop {
+                match event_loop.poll().await {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        println!("Connected to the MQTT broker for the alerts");
+                        reconnect.reset();
+                    },
+                    Ok(_) => {},
+                    Err(e) => reconnect.wait(&e).await,
+                }
+            }
+        });
+        let kinds = if kinds.is_empty() { ALERT_KINDS.iter().map(|kind| kind.to_string()).collect() } else { kinds.to_vec() };
+        AlertSink { client, kinds, states: Mutex::new(HashMap::new()) }
+    }
+
+    /// True if the result is a state of the selected processes.
+    pub fn is_alert(&self, process: &ProcessType, data: &ProcessData) -> bool {
+        let is_state = matches!(data, ProcessData::Threshold(_) | ProcessData::Watchdog(_) | ProcessData::ForecastAlert(_) | ProcessData::ConsistencyStatus(_));
+        is_state && self.kinds.iter().any(|kind| kind == process.kind())
+    }
+
+    /// Publishes the state if it has changed.
+    pub async fn publish(&self, output_topic: &str, source_topic: &str, payload: &str, dry_run: bool) {
+        let topic = alert_topic(output_topic, source_topic);
+        if !changes(&mut *self.states.lock().await, &topic, payload) {
+            return;
+        }
+        println!("Publishing alert to MQTT topic: {}, payload: {}", &topic, payload);
+        if dry_run {
+            return;
+        }
+        if let Err(e) = self.client.publish(topic.clone(), QoS::AtLeastOnce, true, payload.to_string()).await {
+            println!("Error publishing alert to MQTT topic: {}, {:?}", &topic, e);
+        }
+    }
+}
+
+// Records the state, and returns true if it differs from the previous one.
+fn changes(states: &mut HashMap<String, String>, topic: &str, payload: &str) -> bool {
+    states.insert(topic.to_string(), payload.to_string()).as_deref() != Some(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alert_topic() {
+        assert_eq!(alert_topic("i483-sensors-s2420010-SCD41-co2_threshold-crossed", "i483-sensors-s2420010-SCD41-co2"),
+            "i483/alerts/s2420010/i483-sensors-s2420010-SCD41-co2_threshold-crossed");
+        assert_eq!(alert_topic("other-topic_status", "other-topic"), "i483/alerts/other-topic_status");
+    }
+
+    #[test]
+    fn test_only_state_changes() {
+        let mut states = HashMap::new();
+        assert!(changes(&mut states, "i483/alerts/s2420010/co2", "yes"));
+        assert!(!changes(&mut states, "i483/alerts/s2420010/co2", "yes"));
+        assert!(changes(&mut states, "i483/alerts/s2420011/co2", "yes"));
+        assert!(changes(&mut states, "i483/alerts/s2420010/co2", "no"));
+    }
+}
@@ -0,0 +1,191 @@
+/*
+    This is the bridge module. It subscribes to the MQTT topic filters of the ESP32 nodes and produces the messages to Kafka.
+    * The sensor topics `i483/sensors/E/S/T` are named by the topic template, and the other topics (e.g. the JSON document
+      of mqtt_pub.py) are flattened with `-`. The records are keyed by the entity.
+    * The timestamp of the record is the time the message was received from the broker, and the MQTT topic and QoS are in the headers.
+    * At-least-once: a QoS 1/2 message is acknowledged to the broker only after Kafka acknowledged the record. The session is persistent,
+      so the broker redelivers the unacknowledged messages after a restart. A failed record is produced again until it is delivered.
+*/
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use rdkafka::config::ClientConfig;
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use rdkafka::message::{Header, OwnedHeaders};
+use rdkafka::producer::{DeliveryFuture, FutureProducer, FutureRecord};
+use rumqttc::v5::{Event, EventLoop};
+use rumqttc::v5::mqttbytes::QoS;
+use rumqttc::v5::mqttbytes::v5::{Filter, Packet, Publish};
+use uuid::Uuid;
+use i483_sensors::SensorTopic;
+use crate::cli::Args;
+use crate::mqtt::{self, Reconnect};
+
+pub const DEFAULT_TEMPLATE: &str = "i483-sensors-{entity}-{sensor}-{data_type}";
+// The broker keeps the unacknowledged messages for a day while the bridge is down.
+const SESSION_EXPIRY: u32 = 86400;
+
+
+/// The template may contain `{entity}`, `{sensor}` and `{data_type}`.
+pub fn is_valid_template(template: &str) -> bool {
+    let rest = ["{entity}", "{sensor}", "{data_type}"].iter().fold(template.to_string(), |rest, placeholder| rest.replace(placeholder, ""));
+    !template.is_empty() && !rest.contains(['{', '}'])
+}
+
+/// The Kafka topic of an MQTT topic.
+pub fn kafka_topic(template: &str, mqtt_topic: &str) -> String {
+    match SensorTopic::parse(mqtt_topic) {
+        Some(topic) => template
+            .replace("{entity}", &topic.entity)
+            .replace("{sensor}", topic.sensor.name())
+            .replace("{data_type}", topic.data_type.name()),
+        None => mqtt_topic.trim_matches('/').replace('/', "-"),
+    }
+}
+
+/// The entity of `i483/sensors/E/...`, including the document topics `i483/sensors/E/json`.
+fn entity(mqtt_topic: &str) -> Option<String> {
+    if let Some(topic) = SensorTopic::parse(mqtt_topic) {
+        return Some(topic.entity);
+    }
+    let parts: Vec<&str> = mqtt_topic.split('/').collect();
+    match parts.as_slice() {
+        ["i483", "sensors", entity, _] if !entity.is_empty() => Some(entity.to_string()),
+        _ => None,
+    }
+}
+
+// A received MQTT message on the way to Kafka.
+struct Forward {
+    mqtt_topic: String,
+    topic: String,
+    key: String,
+    payload: Vec<u8>,
+    received_at: DateTime<Utc>,
+    qos: u8,
+    correlation_id: String,
+}
+
+impl Forward {
+    fn new(publish: &Publish, template: &str) -> Forward {
+        let mqtt_topic = String::from_utf8_lossy(&publish.topic).to_string();
+        Forward {
+            topic: kafka_topic(template, &mqtt_topic),
+            key: entity(&mqtt_topic).unwrap_or(mqtt_topic.clone()),
+            payload: publish.payload.to_vec(),
+            received_at: Utc::now(),
+            qos: publish.qos as u8,
+            correlation_id: Uuid::new_v4().to_string(),
+            mqtt_topic,
+        }
+    }
+
+    fn record(&self) -> FutureRecord<'_, str, [u8]> {
+        let headers = OwnedHeaders::new()
+            .insert(Header { key: "correlation-id", value: Some(&self.correlation_id) })
+            .insert(Header { key: "mqtt-topic", value: Some(&self.mqtt_topic) })
+            .insert(Header { key: "mqtt-qos", value: Some(&self.qos.to_string()) });
+        FutureRecord::to(&self.topic)
+            .key(self.key.as_str())
+            .payload(&self.payload[..])
+            .headers(headers)
+            .timestamp(self.received_at.timestamp_millis())
+    }
+}
+
+// Enqueues the record to the producer, waiting while its queue is full. The records are enqueued in the order of the messages.
+async fn enqueue(producer: &FutureProducer, forward: &Forward) -> Result<DeliveryFuture, KafkaError> {
+    loop {
+        match producer.send_result(forward.record()) {
+            Ok(delivery) => return Ok(delivery),
+            Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), _)) => tokio::time::sleep(Duration::from_millis(100)).await,
+            Err((e, _)) => return Err(e),
+        }
+    }
+}
+
+// Waits for the acknowledgement of Kafka. A failed record is enqueued again, after the records which follow it.
+async fn deliver(producer: &FutureProducer, forward: &Forward, mut delivery: Result<DeliveryFuture, KafkaError>) {
+    loop {
+        let error = match delivery {
+            Ok(future) => match future.await {
+                Ok(Ok(_)) => return,
+                Ok(Err((e, _))) => e,
+                Err(_) => KafkaError::Canceled,
+            },
+            Err(e) => e,
+        };
+        println!("Error producing message to topic: {}, {:?}, retrying", forward.topic, error);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        delivery = enqueue(producer, forward).await;
+    }
+}
+
+pub async fn bridge(args: &Args) {
+    let broker = args.mqtt_broker.as_ref().expect("the bridge is parsed with the broker");
+    let client_id = args.mqtt_client_id.clone().unwrap_or("i483-kafka-bridge".to_string());
+    let producer: FutureProducer = ClientConfig::new()
+        .set("bootstrap.servers", &args.host)
+        .set("acks", "all")
+        .create().unwrap();
+    let (client, mut event_loop): (_, EventLoop) = mqtt::connect(broker, &client_id, SESSION_EXPIRY, true);
+    let filters: Vec<Filter> = args.topics.iter().map(|topic| Filter::new(topic.clone(), QoS::AtLeastOnce)).collect();
+    let mut reconnect = Reconnect::new();
+    println!("Bridging {:?} from {}:{} ({}) to {}", args.topics, broker.0, broker.1, client_id, args.host);
+    loop {
+        match event_loop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(connack))) => {
+                println!("Connected to the MQTT broker, session present: {}", connack.session_present);
+                reconnect.reset();
+                // The request is queued without waiting, since the event loop which sends it is this loop.
+                if !connack.session_present {
+                    if let Err(e) = client.try_subscribe_many(filters.clone()) {
+                        println!("Error subscribing to {:?}: {:?}", args.topics, e);
+                    }
+                }
+            },
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                let forward = Forward::new(&publish, &args.topic_template);
+                if args.debug {
+                    println!("Received MQTT message from topic: {}, qos: {}, payload: {:?}", forward.mqtt_topic, forward.qos, forward.payload);
+                }
+                if args.dry_run {
+                    println!("Bridging message from {} to {}, key: {}", forward.mqtt_topic, forward.topic, forward.key);
+                    let _ = client.try_ack(&publish);
+                    continue;
+                }
+                let delivery = enqueue(&producer, &forward).await;
+                let (producer, client) = (producer.clone(), client.clone());
+                tokio::spawn(async move {
+                    deliver(&producer, &forward, delivery).await;
+                    println!("Bridged message from {} to {}, key: {}", forward.mqtt_topic, forward.topic, forward.key);
+                    if let Err(e) = client.ack(&publish).await {
+                        println!("Error acknowledging message from {}: {:?}", forward.mqtt_topic, e);
+                    }
+                });
+            },
+            Ok(_) => {},
+            Err(e) => reconnect.wait(&e).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kafka_topic() {
+        assert_eq!(kafka_topic(DEFAULT_TEMPLATE, "i483/sensors/s2420010/SCD41/co2"), "i483-sensors-s2420010-SCD41-co2");
+        assert_eq!(kafka_topic("raw.{sensor}.{data_type}", "i483/sensors/s2420010/BMP180/air_pressure"), "raw.BMP180.air_pressure");
+        assert_eq!(kafka_topic(DEFAULT_TEMPLATE, "i483/sensors/s2420010/json"), "i483-sensors-s2420010-json");
+        assert!(is_valid_template("raw.{sensor}.{data_type}"));
+        assert!(!is_valid_template("raw.{node}"));
+    }
+
+    #[test]
+    fn test_record_key_is_entity() {
+        assert_eq!(entity("i483/sensors/s2420010/SCD41/co2").as_deref(), Some("s2420010"));
+        assert_eq!(entity("i483/sensors/s2420010/msgpack").as_deref(), Some("s2420010"));
+        assert_eq!(entity("i483/status"), None);
+    }
+}
@@ -1,21 +1,125 @@
 use anyhow::anyhow;
+use regex::Regex;
+use serde::Serialize;
+use crate::alert::ALERT_KINDS;
+use crate::bridge::{self, DEFAULT_TEMPLATE};
+use crate::downsample::Reducer;
+use crate::expression::Program;
+use crate::mqtt::parse_broker;
+use crate::ordering::DedupKey;
+use crate::transform::{CalibrationRule, ConversionRule};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Command {
     Help,
     Listen(Args),
     Process(Args),
+    Bridge(Args),
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum ProcessType {
     RollingAverage(u64),
     Threshold(u64), // When the average is above this threshold, send an alert. and when it's below, send a recovery alert.
+    Watchdog { period: u64, multiple: u64, per_entity: bool }, // Send offline when nothing arrives within period * multiple seconds, and online on recovery.
+    Percentile { duration: u64, bucket_width: u64 }, // Emit p50/p90/p99 and the histogram (0 disables it) of every window of duration seconds.
+    Forecast { horizon: u64, threshold: u64, window: u64 }, // Emit the linear trend of the window horizon seconds ahead, and alert when it will cross the threshold (0 disables it).
+    Hampel { window: u64, n_sigmas: u64 }, // Republish the stream without the spikes (Hampel filter over window samples) and report the replaced samples.
+    Consistency { tolerance_milli: u64, timeout: u64 }, // Compare the sensors of the same data type on an entity, and alert on the drift over tolerance / 1000 or a stale sensor.
+    Downsample { period: u64, reducer: Reducer }, // Reduce the samples of every period to one sample for the long-term topic.
+    Expression { name: String, program: Program }, // Emit the values of a user-defined expression (see expression.rs) to <DATA_TYPE>_<NAME>.
+}
+
+/// A parameter of the process in the envelope.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Param {
+    Number(u64),
+    Text(String),
+}
+
+impl ProcessType {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ProcessType::RollingAverage(_) => "rolling-average",
+            ProcessType::Threshold(_) => "threshold",
+            ProcessType::Watchdog { per_entity: false, .. } => "watchdog",
+            ProcessType::Watchdog { per_entity: true, .. } => "entity-watchdog",
+            ProcessType::Percentile { .. } => "percentile",
+            ProcessType::Forecast { .. } => "forecast",
+            ProcessType::Hampel { .. } => "hampel",
+            ProcessType::Consistency { .. } => "consistency",
+            ProcessType::Downsample { .. } => "downsample",
+            ProcessType::Expression { .. } => "expression",
+        }
+    }
+
+    pub fn params(&self) -> Vec<(&'static str, Param)> {
+        let numbers = match self {
+            ProcessType::RollingAverage(duration) => vec![("duration", *duration)],
+            ProcessType::Threshold(baseline) => vec![("baseline", *baseline)],
+            ProcessType::Watchdog { period, multiple, .. } => vec![("period", *period), ("multiple", *multiple)],
+            ProcessType::Percentile { duration, bucket_width } => vec![("duration", *duration), ("bucket_width", *bucket_width)],
+            ProcessType::Forecast { horizon, threshold, window } => vec![("horizon", *horizon), ("threshold", *threshold), ("window", *window)],
+            ProcessType::Hampel { window, n_sigmas } => vec![("window", *window), ("n_sigmas", *n_sigmas)],
+            ProcessType::Consistency { tolerance_milli, timeout } => vec![("tolerance_milli", *tolerance_milli), ("timeout", *timeout)],
+            ProcessType::Downsample { period, reducer } => return vec![("period", Param::Number(*period)), ("reducer", Param::Text(reducer.to_string()))],
+            ProcessType::Expression { program, .. } => return vec![("source", Param::Text(program.source().to_string()))],
+        };
+        numbers.into_iter().map(|(name, value)| (name, Param::Number(value))).collect()
+    }
+
+    // The processes which need the periodic tick.
+    pub fn is_timed(&self) -> bool {
+        matches!(self, ProcessType::Watchdog { .. } | ProcessType::Percentile { .. } | ProcessType::Downsample { .. })
+    }
+
+    // The seconds after the last reading until the last emit of a timed process (the last window or the offline report).
+    // The state is kept at least until then, even after the idle timeout.
+    pub fn final_emit_after(&self) -> u64 {
+        match self {
+            ProcessType::Watchdog { period, multiple, .. } => period * multiple,
+            ProcessType::Percentile { duration, .. } => *duration,
+            ProcessType::Downsample { period, .. } => *period,
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputFormat {
+    Raw, // The bare string which is expected by the course topics.
+    Json,
+    MessagePack,
+    Avro,     // Confluent wire format with the schema registry.
+    Protobuf, // Confluent wire format with the schema registry.
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationPolicy {
+    Quarantine, // Republish the original record to <TOPIC>_invalid with the reason.
+    Drop,
+    DeadLetter, // Republish the original record to the dead letter topic with the reason.
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum InputFormat {
+    Raw,
+    Avro,
+    Protobuf,
 }
 
 pub fn print_usage() {
     println!("Usage 1: kafka-publisher listen --host <host> --topics <topic1> <topic2> ...");
-    println!("Usage 2: kafka-publisher process --host <host> --topics <topic1> --processes <process1> ...");
+    println!("Usage 2: kafka-publisher process --host <host> --topics <topic1> --processes <process1> ... [--output-format raw|json|msgpack|avro|protobuf] [--pipeline-id <id>]");
+    println!("Usage 3: kafka-publisher bridge --host <host> --mqtt-broker <host>[:<port>] [--topics <mqtt filter1> ...] [--topic-template <template>] [--mqtt-client-id <id>]");
+    println!("Alerts: --mqtt-broker <host>[:<port>] [--mqtt-alerts threshold,watchdog,entity-watchdog,forecast,consistency] (process, publishes the state changes to MQTT)");
+    println!("Options: --input-format raw|avro|protobuf --schema-registry <url|local> --idle-timeout <seconds>");
+    println!("Transform: --calibrate <selector>:offset=<offset>,gain=<gain>|table=<raw>/<true>;... --convert <selector>:[<from>:]<to>");
+    println!("Ordering: --dedup <seconds> --dedup-by key|hash --reorder <milliseconds> --max-age <milliseconds>");
+    println!("Validation: --validate quarantine|drop|dead-letter --stuck-after <samples> --dead-letter-topic <topic>");
+    println!("Processes: rolling-average:<seconds> threshold:<baseline> watchdog:<period>[:<multiple>] entity-watchdog:<period>[:<multiple>] percentile:<seconds>[:<bucket width>] forecast:<horizon>[:<threshold>[:<window>]] hampel:<samples>[:<sigmas>] consistency:<tolerance>[:<timeout>] downsample:<seconds>[:mean|last|max|lttb] expr:<name>:<expression>");
+    println!("Topics which start with `^` are regex patterns, e.g. \"^i483-sensors-.*-SCD41-co2$\"");
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -25,6 +129,24 @@ pub struct Args {
     pub processes: Vec<ProcessType>,
     pub debug: bool,
     pub dry_run: bool,
+    pub output_format: OutputFormat,
+    pub pipeline_id: Option<String>,
+    pub input_format: InputFormat,
+    pub schema_registry: Option<String>,
+    pub idle_timeout: u64, // seconds until the state of an inactive topic is evicted.
+    pub validation: Option<ValidationPolicy>,
+    pub stuck_after: usize, // 0 disables the stuck value detection.
+    pub dead_letter_topic: String,
+    pub calibrations: Vec<CalibrationRule>,
+    pub conversions: Vec<ConversionRule>,
+    pub dedup_horizon: u64, // seconds, 0 disables the deduplication.
+    pub dedup_by: DedupKey,
+    pub reorder_tolerance: u64, // milliseconds, 0 disables the reordering.
+    pub max_age: u64, // milliseconds, the older messages are skipped (0 disables it). At least the reorder tolerance and a margin.
+    pub mqtt_broker: Option<(String, u16)>,
+    pub mqtt_client_id: Option<String>, // The persistent session of the bridge is found by the client id.
+    pub topic_template: String, // The Kafka topic of the bridged sensor topics.
+    pub mqtt_alerts: Vec<String>, // The kinds of the processes whose states are published to MQTT, all of them if empty.
 }
 
 pub fn parse_args(args: Vec<String>) -> Command {
@@ -36,6 +158,24 @@ pub fn parse_args(args: Vec<String>) -> Command {
     let mut processes = Vec::new();
     let mut debug = false;
     let mut dry_run = false;
+    let mut output_format = OutputFormat::Raw;
+    let mut pipeline_id = None;
+    let mut input_format = InputFormat::Raw;
+    let mut schema_registry = None;
+    let mut idle_timeout = 600;
+    let mut validation = None;
+    let mut stuck_after = 0;
+    let mut dead_letter_topic = "i483-sensors-dead-letter".to_string();
+    let mut calibrations = Vec::new();
+    let mut conversions = Vec::new();
+    let mut dedup_horizon = 0;
+    let mut dedup_by = DedupKey::KeyAndTimestamp;
+    let mut reorder_tolerance = 0;
+    let mut max_age = 3000;
+    let mut mqtt_broker = None;
+    let mut mqtt_client_id = None;
+    let mut topic_template = DEFAULT_TEMPLATE.to_string();
+    let mut mqtt_alerts = Vec::new();
 
     let mut cursor = 0;
 
@@ -59,6 +199,9 @@ pub fn parse_args(args: Vec<String>) -> Command {
                         if topic.contains("--") {
                             cursor -= 1;
                             seeking = false;
+                        } else if topic.starts_with('^') && Regex::new(&topic).is_err() {
+                            println!("Invalid topic pattern `{}`", topic);
+                            return Command::Help;
                         } else {
                             topics.push(topic);
                         }
@@ -77,7 +220,14 @@ pub fn parse_args(args: Vec<String>) -> Command {
                             cursor -= 1;
                             seeking = false;
                         } else {
-                            processes.push(parse_process(&process));
+                            let process = match parse_process(&process) {
+                                Ok(process) => process,
+                                Err(e) => {
+                                    println!("Invalid process `{}`: {}", process, e);
+                                    return Command::Help;
+                                },
+                            };
+                            processes.push(process);
                         }
                     }
                 }
@@ -88,28 +238,282 @@ pub fn parse_args(args: Vec<String>) -> Command {
             "--dry-run" => {
                 dry_run = true;
             },
+            "--output-format" => {
+                let format = args.get(cursor + 1).ok_or(anyhow!("no argument found for option")).unwrap().clone();
+                match parse_output_format(&format) {
+                    Some(format) => output_format = format,
+                    None => return Command::Help,
+                }
+                cursor += 1;
+            },
+            "--pipeline-id" => {
+                let id = args.get(cursor + 1).ok_or(anyhow!("no argument found for option")).unwrap().clone();
+                if id.contains("--") {
+                    return Command::Help;
+                }
+                pipeline_id = Some(id);
+                cursor += 1;
+            },
+            "--input-format" => {
+                let format = args.get(cursor + 1).ok_or(anyhow!("no argument found for option")).unwrap().clone();
+                match parse_input_format(&format) {
+                    Some(format) => input_format = format,
+                    None => return Command::Help,
+                }
+                cursor += 1;
+            },
+            "--schema-registry" => {
+                let url = args.get(cursor + 1).ok_or(anyhow!("no argument found for option")).unwrap().clone();
+                if url.contains("--") {
+                    return Command::Help;
+                }
+                schema_registry = Some(url);
+                cursor += 1;
+            },
+            "--idle-timeout" => {
+                match args.get(cursor + 1).and_then(|value| value.parse().ok()) {
+                    Some(value) => idle_timeout = value,
+                    None => return Command::Help,
+                }
+                cursor += 1;
+            },
+            "--validate" => {
+                let policy = args.get(cursor + 1).ok_or(anyhow!("no argument found for option")).unwrap().clone();
+                match parse_validation_policy(&policy) {
+                    Some(policy) => validation = Some(policy),
+                    None => return Command::Help,
+                }
+                cursor += 1;
+            },
+            "--stuck-after" => {
+                match args.get(cursor + 1).and_then(|value| value.parse().ok()) {
+                    Some(value) => stuck_after = value,
+                    None => return Command::Help,
+                }
+                cursor += 1;
+            },
+            "--dead-letter-topic" => {
+                let topic = args.get(cursor + 1).ok_or(anyhow!("no argument found for option")).unwrap().clone();
+                if topic.contains("--") {
+                    return Command::Help;
+                }
+                dead_letter_topic = topic;
+                cursor += 1;
+            },
+            "--calibrate" => {
+                match args.get(cursor + 1).and_then(|rule| CalibrationRule::parse(rule)) {
+                    Some(rule) => calibrations.push(rule),
+                    None => return Command::Help,
+                }
+                cursor += 1;
+            },
+            "--convert" => {
+                match args.get(cursor + 1).and_then(|rule| ConversionRule::parse(rule)) {
+                    Some(rule) => conversions.push(rule),
+                    None => return Command::Help,
+                }
+                cursor += 1;
+            },
+            "--dedup" => {
+                match args.get(cursor + 1).and_then(|value| value.parse().ok()) {
+                    Some(value) => dedup_horizon = value,
+                    None => return Command::Help,
+                }
+                cursor += 1;
+            },
+            "--dedup-by" => {
+                match args.get(cursor + 1).and_then(|key| parse_dedup_key(key)) {
+                    Some(key) => dedup_by = key,
+                    None => return Command::Help,
+                }
+                cursor += 1;
+            },
+            "--reorder" => {
+                match args.get(cursor + 1).and_then(|value| value.parse().ok()) {
+                    Some(value) => reorder_tolerance = value,
+                    None => return Command::Help,
+                }
+                cursor += 1;
+            },
+            "--max-age" => {
+                match args.get(cursor + 1).and_then(|value| value.parse().ok()) {
+                    Some(value) => max_age = value,
+                    None => return Command::Help,
+                }
+                cursor += 1;
+            },
+            "--mqtt-broker" => {
+                match args.get(cursor + 1).and_then(|broker| parse_broker(broker)) {
+                    Some(broker) => mqtt_broker = Some(broker),
+                    None => return Command::Help,
+                }
+                cursor += 1;
+            },
+            "--mqtt-client-id" => {
+                let id = args.get(cursor + 1).ok_or(anyhow!("no argument found for option")).unwrap().clone();
+                if id.contains("--") {
+                    return Command::Help;
+                }
+                mqtt_client_id = Some(id);
+                cursor += 1;
+            },
+            "--topic-template" => {
+                match args.get(cursor + 1).filter(|template| bridge::is_valid_template(template)) {
+                    Some(template) => topic_template = template.clone(),
+                    None => return Command::Help,
+                }
+                cursor += 1;
+            },
+            "--mqtt-alerts" => {
+                let kinds: Vec<String> = args.get(cursor + 1).map(|kinds| kinds.split(',').map(|kind| kind.to_ascii_lowercase()).collect()).unwrap_or_default();
+                if kinds.is_empty() || kinds.iter().any(|kind| !ALERT_KINDS.contains(&kind.as_str())) {
+                    return Command::Help;
+                }
+                mqtt_alerts = kinds;
+                cursor += 1;
+            },
             _ => {},
         }
         cursor += 1;
     }
 
-    println!("Initializing with host: {}, topics: {:?}, processes: {:?}, debug: {}, dry_run: {}", host, topics, processes, debug, dry_run);
+    println!("Initializing with host: {}, topics: {:?}, processes: {:?}, debug: {}, dry_run: {}, output_format: {:?}", host, topics, processes, debug, dry_run, output_format);
     match args[1].as_str() {
-        "listen" => Command::Listen(Args { host, topics, processes, debug, dry_run }),
-        "process" => Command::Process(Args { host, topics, processes, debug, dry_run }),
+        "listen" => Command::Listen(Args { host, topics, processes, debug, dry_run, output_format, pipeline_id, input_format, schema_registry, idle_timeout, validation, stuck_after, dead_letter_topic, calibrations, conversions, dedup_horizon, dedup_by, reorder_tolerance, max_age, mqtt_broker, mqtt_client_id, topic_template, mqtt_alerts }),
+        // Every topic is paired with the process of the same position.
+        "process" if topics.len() != processes.len() => {
+            println!("{} topics are given for {} processes", topics.len(), processes.len());
+            Command::Help
+        },
+        "process" => Command::Process(Args { host, topics, processes, debug, dry_run, output_format, pipeline_id, input_format, schema_registry, idle_timeout, validation, stuck_after, dead_letter_topic, calibrations, conversions, dedup_horizon, dedup_by, reorder_tolerance, max_age, mqtt_broker, mqtt_client_id, topic_template, mqtt_alerts }),
+        // The topics of the bridge are the MQTT topic filters.
+        "bridge" if mqtt_broker.is_some() => {
+            let topics = if topics.is_empty() { vec!["i483/sensors/#".to_string()] } else { topics };
+            Command::Bridge(Args { host, topics, processes, debug, dry_run, output_format, pipeline_id, input_format, schema_registry, idle_timeout, validation, stuck_after, dead_letter_topic, calibrations, conversions, dedup_horizon, dedup_by, reorder_tolerance, max_age, mqtt_broker, mqtt_client_id, topic_template, mqtt_alerts })
+        },
         _ => Command::Help,
     }
 }
 
 
-fn parse_process(process: &str) -> ProcessType {
+// An unknown process type falls back to the rolling average. The value and the options of a known type must be valid.
+fn parse_process(process: &str) -> Result<ProcessType, String> {
+    if let Some((process_type, rest)) = process.split_once(':') {
+        match process_type.to_ascii_lowercase().as_str() {
+            // The source of an expression may contain `:`, so it is split only twice.
+            "expr" | "expression" => {
+                let (name, source) = rest.split_once(':').unwrap_or(("expr", rest));
+                let program = Program::compile(source).map_err(|e| format!("invalid expression `{}`: {}", source, e))?;
+                return Ok(ProcessType::Expression { name: name.to_string(), program });
+            },
+            // The tolerance may have decimals, e.g. 1.5 °C.
+            "consistency" => {
+                let options: Vec<&str> = rest.split(':').collect();
+                let tolerance: f64 = match options[0].parse() {
+                    Ok(tolerance) if tolerance >= 0.0 => tolerance,
+                    _ => return Err(format!("invalid tolerance `{}`", options[0])),
+                };
+                if options.len() > 2 {
+                    return Err(format!("too many options in `{}`", process));
+                }
+                return Ok(ProcessType::Consistency {
+                    tolerance_milli: (tolerance * 1000.0).round() as u64,
+                    timeout: option(&options, 1, 60)?,
+                });
+            },
+            _ => {},
+        }
+    }
     let mut parts = process.split(":");
-    let process_type = parts.next().unwrap();
-    let process_value = parts.next().unwrap().parse().unwrap();
-    match process_type.to_ascii_lowercase().as_str() {
+    let process_type = parts.next().unwrap_or_default().to_ascii_lowercase();
+    let value = parts.next();
+    let options: Vec<&str> = parts.collect();
+    let max_options = match process_type.as_str() {
+        "rolling-average" | "threshold" => 0,
+        "watchdog" | "entity-watchdog" | "percentile" | "hampel" | "downsample" => 1,
+        "forecast" => 2,
+        _ => return Ok(ProcessType::RollingAverage(0)),
+    };
+    let process_value = match value.map(|value| value.parse()) {
+        Some(Ok(value)) => value,
+        _ => return Err(format!("invalid value in `{}`", process)),
+    };
+    if options.len() > max_options {
+        return Err(format!("too many options in `{}`", process));
+    }
+    Ok(match process_type.as_str() {
         "rolling-average" => ProcessType::RollingAverage(process_value),
         "threshold" => ProcessType::Threshold(process_value),
-        _ => ProcessType::RollingAverage(0),
+        "watchdog" | "entity-watchdog" => ProcessType::Watchdog {
+            period: process_value,
+            multiple: option(&options, 0, 3)?,
+            per_entity: process_type == "entity-watchdog",
+        },
+        "percentile" => ProcessType::Percentile {
+            duration: process_value,
+            bucket_width: option(&options, 0, 0)?,
+        },
+        "forecast" => ProcessType::Forecast {
+            horizon: process_value,
+            threshold: option(&options, 0, 0)?,
+            window: option(&options, 1, 600)?,
+        },
+        "hampel" => ProcessType::Hampel {
+            window: process_value,
+            n_sigmas: option(&options, 0, 3)?,
+        },
+        _ => ProcessType::Downsample {
+            period: process_value,
+            reducer: match options.first() {
+                Some(reducer) => Reducer::parse(reducer).ok_or(format!("unknown reducer `{}`", reducer))?,
+                None => Reducer::Mean,
+            },
+        },
+    })
+}
+
+// The numeric option at the index, or the default when it is omitted.
+fn option(options: &[&str], index: usize, default: u64) -> Result<u64, String> {
+    match options.get(index) {
+        Some(value) => value.parse().map_err(|_| format!("invalid option `{}`", value)),
+        None => Ok(default),
+    }
+}
+
+fn parse_output_format(format: &str) -> Option<OutputFormat> {
+    match format.to_ascii_lowercase().as_str() {
+        "raw" => Some(OutputFormat::Raw),
+        "json" => Some(OutputFormat::Json),
+        "msgpack" | "messagepack" => Some(OutputFormat::MessagePack),
+        "avro" => Some(OutputFormat::Avro),
+        "protobuf" | "proto" => Some(OutputFormat::Protobuf),
+        _ => None,
+    }
+}
+
+fn parse_validation_policy(policy: &str) -> Option<ValidationPolicy> {
+    match policy.to_ascii_lowercase().as_str() {
+        "quarantine" => Some(ValidationPolicy::Quarantine),
+        "drop" => Some(ValidationPolicy::Drop),
+        "dead-letter" => Some(ValidationPolicy::DeadLetter),
+        _ => None,
+    }
+}
+
+fn parse_dedup_key(key: &str) -> Option<DedupKey> {
+    match key.to_ascii_lowercase().as_str() {
+        "key" => Some(DedupKey::KeyAndTimestamp),
+        "hash" => Some(DedupKey::ContentHash),
+        _ => None,
+    }
+}
+
+fn parse_input_format(format: &str) -> Option<InputFormat> {
+    match format.to_ascii_lowercase().as_str() {
+        "raw" => Some(InputFormat::Raw),
+        "avro" => Some(InputFormat::Avro),
+        "protobuf" | "proto" => Some(InputFormat::Protobuf),
+        _ => None,
     }
 }
 
@@ -119,9 +523,21 @@ mod tests {
 
     #[test]
     fn test_parse_process() {
-        assert_eq!(parse_process("rolling-average:10"), ProcessType::RollingAverage(10));
-        assert_eq!(parse_process("threshold:10"), ProcessType::Threshold(10));
-        assert_eq!(parse_process("invalid:10"), ProcessType::RollingAverage(0));
+        assert_eq!(parse_process("rolling-average:10"), Ok(ProcessType::RollingAverage(10)));
+        assert_eq!(parse_process("threshold:10"), Ok(ProcessType::Threshold(10)));
+        assert_eq!(parse_process("invalid:10"), Ok(ProcessType::RollingAverage(0)));
+        assert_eq!(parse_process("watchdog:15"), Ok(ProcessType::Watchdog { period: 15, multiple: 3, per_entity: false }));
+        assert_eq!(parse_process("entity-watchdog:15:4"), Ok(ProcessType::Watchdog { period: 15, multiple: 4, per_entity: true }));
+        assert_eq!(parse_process("percentile:300:100"), Ok(ProcessType::Percentile { duration: 300, bucket_width: 100 }));
+        assert_eq!(parse_process("forecast:600:1000"), Ok(ProcessType::Forecast { horizon: 600, threshold: 1000, window: 600 }));
+        assert_eq!(parse_process("hampel:7"), Ok(ProcessType::Hampel { window: 7, n_sigmas: 3 }));
+        assert_eq!(parse_process("consistency:1.5"), Ok(ProcessType::Consistency { tolerance_milli: 1500, timeout: 60 }));
+        assert_eq!(parse_process("downsample:300"), Ok(ProcessType::Downsample { period: 300, reducer: Reducer::Mean }));
+        assert_eq!(parse_process("downsample:300:lttb"), Ok(ProcessType::Downsample { period: 300, reducer: Reducer::Lttb }));
+        assert_eq!(parse_process("expr:hpa:value * 0.01"), Ok(ProcessType::Expression { name: "hpa".to_string(), program: Program::compile("value * 0.01").unwrap() }));
+        for malformed in ["consistency:abc", "consistency:1.5:soon", "threshold:high", "watchdog:15:x", "forecast:600:1000:600:1", "downsample:300:median", "rolling-average"] {
+            assert!(parse_process(malformed).is_err(), "{}", malformed);
+        }
     }
 
     #[test]
@@ -136,7 +552,7 @@ mod tests {
             "topic2".to_string(),
         ];
         match parse_args(args) {
-            Command::Listen(Args { host, topics, processes, debug: false, dry_run: false }) => {
+            Command::Listen(Args { host, topics, processes, debug: false, dry_run: false, .. }) => {
                 assert_eq!(host, "localhost:9092");
                 assert_eq!(topics, vec!["topic1".to_string(), "topic2".to_string()]);
                 assert_eq!(processes, vec![]);
@@ -160,7 +576,7 @@ mod tests {
             "threshold:20".to_string(),
         ];
         match parse_args(args) {
-            Command::Process(Args { host, topics, processes, debug: false, dry_run: false }) => {
+            Command::Process(Args { host, topics, processes, debug: false, dry_run: false, .. }) => {
                 assert_eq!(host, "localhost:9092");
                 assert_eq!(topics, vec!["topic1".to_string(), "topic2".to_string()]);
                 assert_eq!(processes, vec![ProcessType::RollingAverage(10), ProcessType::Threshold(20)]);
@@ -184,7 +600,7 @@ mod tests {
             "threshold:20".to_string(),
         ];
         match parse_args(args) {
-            Command::Process(Args { host, topics, processes, debug: false, dry_run: false }) => {
+            Command::Process(Args { host, topics, processes, debug: false, dry_run: false, .. }) => {
                 assert_eq!(host, "localhost:9092");
                 assert_eq!(topics, vec!["topic1".to_string(), "topic2".to_string()]);
                 assert_eq!(processes, vec![ProcessType::RollingAverage(10), ProcessType::Threshold(20)]);
@@ -211,4 +627,186 @@ mod tests {
             _ => panic!("unexpected command"),
         }
     }
+
+    #[test]
+    fn test_parse_args_with_output_format() {
+        let args = vec![
+            "kafka-publisher".to_string(),
+            "process".to_string(),
+            "--host".to_string(),
+            "localhost:9092".to_string(),
+            "--output-format".to_string(),
+            "json".to_string(),
+            "--topics".to_string(),
+            "topic1".to_string(),
+            "--processes".to_string(),
+            "rolling-average:10".to_string(),
+        ];
+        match parse_args(args) {
+            Command::Process(Args { topics, output_format, .. }) => {
+                assert_eq!(topics, vec!["topic1".to_string()]);
+                assert_eq!(output_format, OutputFormat::Json);
+            },
+            _ => panic!("unexpected command"),
+        }
+        assert_eq!(parse_output_format("MsgPack"), Some(OutputFormat::MessagePack));
+        assert_eq!(parse_output_format("xml"), None);
+    }
+
+    #[test]
+    fn test_parse_args_with_schema_registry() {
+        let args = vec![
+            "kafka-publisher".to_string(),
+            "process".to_string(),
+            "--host".to_string(),
+            "localhost:9092".to_string(),
+            "--input-format".to_string(),
+            "avro".to_string(),
+            "--output-format".to_string(),
+            "protobuf".to_string(),
+            "--schema-registry".to_string(),
+            "http://localhost:8081".to_string(),
+        ];
+        match parse_args(args) {
+            Command::Process(Args { input_format, output_format, schema_registry, .. }) => {
+                assert_eq!(input_format, InputFormat::Avro);
+                assert_eq!(output_format, OutputFormat::Protobuf);
+                assert_eq!(schema_registry, Some("http://localhost:8081".to_string()));
+            },
+            _ => panic!("unexpected command"),
+        }
+    }
+
+    #[test]
+    fn test_process_params() {
+        let downsample = ProcessType::Downsample { period: 300, reducer: Reducer::Lttb };
+        assert_eq!(downsample.params(), vec![("period", Param::Number(300)), ("reducer", Param::Text("lttb".to_string()))]);
+        assert_eq!(ProcessType::Threshold(1000).params(), vec![("baseline", Param::Number(1000))]);
+    }
+
+    #[test]
+    fn test_parse_args_rejects_unpaired_topics() {
+        let args = |topics: &[&str], processes: &[&str]| -> Vec<String> {
+            ["kafka-publisher", "process", "--topics"].iter().chain(topics).chain(&["--processes"]).chain(processes).map(|arg| arg.to_string()).collect()
+        };
+        assert!(matches!(parse_args(args(&["^i483-sensors-.*-co2$"], &["threshold:1000"])), Command::Process(_)));
+        assert_eq!(parse_args(args(&["^i483-sensors-(co2"], &["threshold:1000"])), Command::Help);
+        assert_eq!(parse_args(args(&["topic-a", "topic-b"], &["threshold:1000"])), Command::Help);
+        assert_eq!(parse_args(args(&["topic-a"], &["threshold:1000", "watchdog:15"])), Command::Help);
+    }
+
+    #[test]
+    fn test_parse_args_with_validation() {
+        let args = vec![
+            "kafka-publisher".to_string(),
+            "process".to_string(),
+            "--validate".to_string(),
+            "dead-letter".to_string(),
+            "--stuck-after".to_string(),
+            "20".to_string(),
+        ];
+        match parse_args(args) {
+            Command::Process(Args { validation, stuck_after, dead_letter_topic, .. }) => {
+                assert_eq!(validation, Some(ValidationPolicy::DeadLetter));
+                assert_eq!(stuck_after, 20);
+                assert_eq!(dead_letter_topic, "i483-sensors-dead-letter");
+            },
+            _ => panic!("unexpected command"),
+        }
+    }
+
+    #[test]
+    fn test_parse_args_with_transform() {
+        let args = vec![
+            "kafka-publisher".to_string(),
+            "process".to_string(),
+            "--calibrate".to_string(),
+            "s2420010/SCD41/temperature:offset=-1.5".to_string(),
+            "--convert".to_string(),
+            "air_pressure:Pa:hPa".to_string(),
+            "--convert".to_string(),
+            "temperature:K".to_string(),
+        ];
+        match parse_args(args) {
+            Command::Process(Args { calibrations, conversions, .. }) => {
+                assert_eq!(calibrations.len(), 1);
+                assert_eq!(conversions.iter().map(|rule| rule.to.as_str()).collect::<Vec<_>>(), vec!["hPa", "K"]);
+            },
+            _ => panic!("unexpected command"),
+        }
+        let args = vec!["kafka-publisher".to_string(), "process".to_string(), "--convert".to_string(), "temperature:lx".to_string()];
+        assert_eq!(parse_args(args), Command::Help);
+    }
+
+    #[test]
+    fn test_parse_args_with_ordering() {
+        let args = vec![
+            "kafka-publisher".to_string(),
+            "process".to_string(),
+            "--dedup".to_string(),
+            "60".to_string(),
+            "--dedup-by".to_string(),
+            "hash".to_string(),
+            "--reorder".to_string(),
+            "500".to_string(),
+            "--max-age".to_string(),
+            "10000".to_string(),
+        ];
+        match parse_args(args) {
+            Command::Process(Args { dedup_horizon, dedup_by, reorder_tolerance, max_age, .. }) => {
+                assert_eq!(dedup_horizon, 60);
+                assert_eq!(dedup_by, DedupKey::ContentHash);
+                assert_eq!(reorder_tolerance, 500);
+                assert_eq!(max_age, 10000);
+            },
+            _ => panic!("unexpected command"),
+        }
+        let args = vec!["kafka-publisher".to_string(), "process".to_string(), "--dedup-by".to_string(), "offset".to_string()];
+        assert_eq!(parse_args(args), Command::Help);
+    }
+
+    #[test]
+    fn test_parse_args_with_bridge() {
+        let args = vec![
+            "kafka-publisher".to_string(),
+            "bridge".to_string(),
+            "--host".to_string(),
+            "localhost:9092".to_string(),
+            "--mqtt-broker".to_string(),
+            "150.65.230.59".to_string(),
+            "--topic-template".to_string(),
+            "raw-{entity}-{sensor}-{data_type}".to_string(),
+        ];
+        match parse_args(args) {
+            Command::Bridge(Args { topics, mqtt_broker, topic_template, .. }) => {
+                assert_eq!(topics, vec!["i483/sensors/#".to_string()]);
+                assert_eq!(mqtt_broker, Some(("150.65.230.59".to_string(), 1883)));
+                assert_eq!(topic_template, "raw-{entity}-{sensor}-{data_type}");
+            },
+            _ => panic!("unexpected command"),
+        }
+        let args = vec!["kafka-publisher".to_string(), "bridge".to_string(), "--host".to_string(), "localhost:9092".to_string()];
+        assert_eq!(parse_args(args), Command::Help);
+    }
+
+    #[test]
+    fn test_parse_args_with_mqtt_alerts() {
+        let args = vec![
+            "kafka-publisher".to_string(),
+            "process".to_string(),
+            "--mqtt-broker".to_string(),
+            "localhost".to_string(),
+            "--mqtt-alerts".to_string(),
+            "threshold,Watchdog".to_string(),
+        ];
+        match parse_args(args) {
+            Command::Process(Args { mqtt_broker, mqtt_alerts, .. }) => {
+                assert_eq!(mqtt_broker, Some(("localhost".to_string(), 1883)));
+                assert_eq!(mqtt_alerts, vec!["threshold".to_string(), "watchdog".to_string()]);
+            },
+            _ => panic!("unexpected command"),
+        }
+        let args = vec!["kafka-publisher".to_string(), "process".to_string(), "--mqtt-alerts".to_string(), "percentile".to_string()];
+        assert_eq!(parse_args(args), Command::Help);
+    }
 }
@@ -0,0 +1,115 @@
+/*
+    This is the consistency module. It compares the redundant measurements of the same data type
+    from the different sensors of an entity (e.g. the temperature of BMP180 and SCD41).
+    * Drift: the smoothed spread (max - min) of the latest values of the sensors.
+    * Status: ok, drift (the drift exceeds the tolerance) or stale (a sensor stopped reporting while the others report).
+*/
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use chrono::{DateTime, Utc};
+
+
+const SMOOTHING: f64 = 0.2; // The weight of the latest spread in the drift.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Status {
+    Ok,
+    Drift,
+    Stale(String), // The sensor which stopped reporting.
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Status::Ok => write!(f, "ok"),
+            Status::Drift => write!(f, "drift"),
+            Status::Stale(sensor) => write!(f, "stale:{}", sensor),
+        }
+    }
+}
+
+pub struct ConsistencyCheck {
+    tolerance: f64,
+    timeout: i64, // seconds
+    latest: BTreeMap<String, (f64, DateTime<Utc>)>, // sensor -> (value, time)
+    drift: Option<f64>,
+    status: Status,
+}
+
+impl ConsistencyCheck {
+    pub fn new(tolerance: f64, timeout: u64) -> ConsistencyCheck {
+        ConsistencyCheck {
+            tolerance,
+            timeout: timeout as i64,
+            latest: BTreeMap::new(),
+            drift: None,
+            status: Status::Ok,
+        }
+    }
+
+    /// Records the value of the sensor. Returns the drift when two or more sensors are fresh,
+    /// and the status when it is changed.
+    pub fn update(&mut self, sensor: &str, value: f64, time: DateTime<Utc>) -> (Option<f64>, Option<Status>) {
+        self.latest.insert(sensor.to_string(), (value, time));
+        let stale = self.latest.iter()
+            .find(|(_, (_, seen))| (time - *seen).num_seconds() > self.timeout)
+            .map(|(sensor, _)| sensor.clone());
+        let fresh: Vec<f64> = self.latest.values()
+            .filter(|(_, seen)| (time - *seen).num_seconds() <= self.timeout)
+            .map(|(value, _)| *value)
+            .collect();
+        if fresh.len() >= 2 {
+            let spread = fresh.iter().cloned().fold(f64::NEG_INFINITY, f64::max) - fresh.iter().cloned().fold(f64::INFINITY, f64::min);
+            self.drift = Some(match self.drift {
+                Some(drift) => drift + SMOOTHING * (spread - drift),
+                None => spread,
+            });
+        }
+        let status = match (stale, self.drift) {
+            (Some(sensor), _) => Status::Stale(sensor),
+            (None, Some(drift)) if drift > self.tolerance => Status::Drift,
+            _ => Status::Ok,
+        };
+        let changed = if status != self.status {
+            self.status = status.clone();
+            Some(status)
+        } else {
+            None
+        };
+        let drift = if fresh.len() >= 2 { self.drift } else { None };
+        (drift, changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn test_drift_exceeds_tolerance() {
+        let start = Utc::now();
+        let mut check = ConsistencyCheck::new(1.5, 60);
+        assert_eq!(check.update("BMP180", 24.0, start), (None, None));
+        assert_eq!(check.update("SCD41", 25.0, start), (Some(1.0), None));
+        let mut changed = None;
+        for i in 1..=10 {
+            let time = start + Duration::seconds(i * 15);
+            for (sensor, value) in [("BMP180", 24.0), ("SCD41", 27.0)] {
+                changed = changed.or(check.update(sensor, value, time).1);
+            }
+        }
+        assert_eq!(changed, Some(Status::Drift));
+        assert!(check.drift.unwrap() > 1.5);
+    }
+
+    #[test]
+    fn test_stale_sensor() {
+        let start = Utc::now();
+        let mut check = ConsistencyCheck::new(1.5, 60);
+        check.update("BMP180", 24.0, start);
+        check.update("SCD41", 24.2, start);
+        assert_eq!(check.update("BMP180", 24.1, start + Duration::seconds(61)), (None, Some(Status::Stale("SCD41".to_string()))));
+        assert_eq!(check.update("SCD41", 24.3, start + Duration::seconds(75)).1, Some(Status::Ok));
+    }
+}
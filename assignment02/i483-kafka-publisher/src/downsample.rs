@@ -0,0 +1,144 @@
+/*
+    This is the downsample module. It reduces the samples of every period to a single sample,
+    so the long-term topics keep months of data at a coarser resolution (e.g. 15 s to 5 min).
+    * mean, last, max: the reduction of the samples of the period.
+    * lttb: Largest-Triangle-Three-Buckets (Steinarsson, "Downsampling Time Series for Visual Representation", 2013).
+      The sample of the period which forms the largest triangle with the previous pick and the mean of the next period
+      is picked, so the peaks and the dips of the stream are kept. A period is picked when the next period is closed,
+      so the output lags one period. The last period before a gap is picked against its own last sample.
+*/
+use std::fmt::{self, Display, Formatter};
+use chrono::{DateTime, Utc};
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reducer {
+    Mean,
+    Last,
+    Max,
+    Lttb,
+}
+
+impl Reducer {
+    pub fn parse(reducer: &str) -> Option<Reducer> {
+        match reducer.to_ascii_lowercase().as_str() {
+            "mean" | "avg" => Some(Reducer::Mean),
+            "last" => Some(Reducer::Last),
+            "max" => Some(Reducer::Max),
+            "lttb" => Some(Reducer::Lttb),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Reducer {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Reducer::Mean => write!(f, "mean"),
+            Reducer::Last => write!(f, "last"),
+            Reducer::Max => write!(f, "max"),
+            Reducer::Lttb => write!(f, "lttb"),
+        }
+    }
+}
+
+type Sample = (DateTime<Utc>, f64);
+
+/// The samples of the current period. T is the description of the period (e.g. the window), which is returned with the result.
+pub struct Downsampler<T> {
+    reducer: Reducer,
+    samples: Vec<Sample>,
+    pending: Option<(T, Vec<Sample>)>, // LTTB: the closed period which waits for the next one.
+    previous: Option<Sample>, // LTTB: the latest pick.
+}
+
+// The doubled area of the triangle, enough to compare the triangles.
+fn triangle_area(a: Sample, b: Sample, c: Sample) -> f64 {
+    let seconds = |time: DateTime<Utc>| (time - a.0).num_milliseconds() as f64 / 1000.0;
+    let (bx, cx) = (seconds(b.0), seconds(c.0));
+    (bx * (c.1 - a.1) - cx * (b.1 - a.1)).abs()
+}
+
+impl<T> Downsampler<T> {
+    pub fn new(reducer: Reducer) -> Downsampler<T> {
+        Downsampler {
+            reducer,
+            samples: Vec::new(),
+            pending: None,
+            previous: None,
+        }
+    }
+
+    pub fn add(&mut self, time: DateTime<Utc>, value: f64) {
+        if value.is_finite() {
+            self.samples.push((time, value));
+        }
+    }
+
+    /// Closes the current period. Returns the reduced sample of a period, or None when there is nothing to emit.
+    pub fn close(&mut self, period: T) -> Option<(T, f64)> {
+        let samples = std::mem::take(&mut self.samples);
+        if self.reducer != Reducer::Lttb {
+            let value = match self.reducer {
+                Reducer::Mean => samples.iter().map(|(_, value)| value).sum::<f64>() / samples.len() as f64,
+                Reducer::Last => samples.last()?.1,
+                _ => samples.iter().map(|(_, value)| *value).fold(f64::NEG_INFINITY, f64::max),
+            };
+            return if samples.is_empty() { None } else { Some((period, value)) };
+        }
+        let pending = std::mem::replace(&mut self.pending, if samples.is_empty() { None } else { Some((period, samples.clone())) });
+        let (pending_period, candidates) = pending?;
+        let next = if samples.is_empty() {
+            *candidates.last()?
+        } else {
+            let n = samples.len() as f64;
+            let origin = samples[0].0;
+            let offset = samples.iter().map(|(time, _)| (*time - origin).num_milliseconds() as f64).sum::<f64>() / n;
+            (origin + chrono::Duration::milliseconds(offset as i64), samples.iter().map(|(_, value)| value).sum::<f64>() / n)
+        };
+        let pick = match self.previous {
+            Some(previous) => *candidates.iter()
+                .max_by(|a, b| triangle_area(previous, **a, next).total_cmp(&triangle_area(previous, **b, next)))?,
+            None => candidates[0],
+        };
+        self.previous = Some(pick);
+        Some((pending_period, pick.1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn feed(downsampler: &mut Downsampler<usize>, start: DateTime<Utc>, period: usize, values: &[f64]) {
+        for (i, value) in values.iter().enumerate() {
+            downsampler.add(start + Duration::seconds((period * 300 + i * 15) as i64), *value);
+        }
+    }
+
+    #[test]
+    fn test_reducers() {
+        let start = Utc::now();
+        for (reducer, expected) in [(Reducer::Mean, 815.0), (Reducer::Last, 810.0), (Reducer::Max, 830.0)] {
+            let mut downsampler = Downsampler::new(reducer);
+            feed(&mut downsampler, start, 0, &[805.0, 830.0, 815.0, 810.0]);
+            assert_eq!(downsampler.close(0), Some((0, expected)));
+            assert_eq!(downsampler.close(1), None);
+        }
+    }
+
+    #[test]
+    fn test_lttb_keeps_peak() {
+        let start = Utc::now();
+        let mut downsampler = Downsampler::new(Reducer::Lttb);
+        feed(&mut downsampler, start, 0, &[800.0, 801.0]);
+        assert_eq!(downsampler.close(0), None);
+        feed(&mut downsampler, start, 1, &[802.0, 1200.0, 803.0, 801.0]);
+        assert_eq!(downsampler.close(1), Some((0, 800.0)));
+        feed(&mut downsampler, start, 2, &[805.0, 804.0]);
+        assert_eq!(downsampler.close(2), Some((1, 1200.0)));
+        assert_eq!(downsampler.close(3), Some((2, 805.0)));
+        assert_eq!(downsampler.close(4), None);
+    }
+}
@@ -0,0 +1,542 @@
+/*
+    This is the expression module. It is a small sandboxed language for the ad-hoc transforms
+    and filters, e.g. `value * 0.01` or `if value > prev + 50 { emit(value) }`.
+
+    * Variables: value (the reading), prev (the previous reading of the topic), count (the number of readings).
+    * Functions: emit(x), latest("topic"), latest("topic", default), abs, sqrt, round, floor, ceil, min, max, clamp.
+    * Statements: `let x = <expr>; ...`, `if <cond> { ... } else { ... }`.
+    * Operators: + - * / % < <= > >= == != && || ! (true is 1, false is 0).
+
+    A program without emit() emits the value of its last statement. There are no loops, no I/O, and
+    the size of the program, the evaluation steps and the emitted values are limited, so the evaluation
+    is deterministic and always terminates.
+*/
+use std::collections::{HashMap, HashSet};
+
+
+const MAX_SOURCE_LENGTH: usize = 1024;
+const MAX_NODES: usize = 256;
+const MAX_DEPTH: usize = 32;
+const MAX_STEPS: usize = 10_000;
+const MAX_EMITS: usize = 16;
+
+const VARIABLES: [&str; 3] = ["value", "prev", "count"];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Text(String),
+    Ident(String),
+    Symbol(&'static str),
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    const SYMBOLS: [&str; 21] = ["<=", ">=", "==", "!=", "&&", "||", "+", "-", "*", "/", "%", "<", ">", "!", "(", ")", "{", "}", ",", ";", "="];
+    let mut tokens = Vec::new();
+    let chars: Vec<char> = source.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() || c == '.' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            let number: String = chars[start..i].iter().collect();
+            tokens.push(Token::Number(number.parse().map_err(|_| format!("invalid number `{}`", number))?));
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else if c == '"' {
+            let start = i + 1;
+            i = start;
+            while i < chars.len() && chars[i] != '"' {
+                i += 1;
+            }
+            if i == chars.len() {
+                return Err("unterminated string".to_string());
+            }
+            tokens.push(Token::Text(chars[start..i].iter().collect()));
+            i += 1;
+        } else {
+            let rest: String = chars[i..chars.len().min(i + 2)].iter().collect();
+            match SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) {
+                Some(symbol) => {
+                    tokens.push(Token::Symbol(symbol));
+                    i += symbol.len();
+                },
+                None => return Err(format!("unexpected character `{}`", c)),
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Number(f64),
+    Text(String),
+    Variable(String),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+    If(Box<Expr>, Vec<Statement>, Option<Vec<Statement>>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Statement {
+    Let(String, Expr),
+    Expr(Expr),
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    cursor: usize,
+    nodes: usize,
+    depth: usize,
+    names: HashSet<String>,
+    emits: bool,
+    topics: Vec<String>,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.cursor)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.cursor).cloned();
+        self.cursor += 1;
+        token
+    }
+
+    fn eat(&mut self, symbol: &'static str) -> bool {
+        if self.peek() == Some(&Token::Symbol(symbol)) {
+            self.cursor += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, symbol: &'static str) -> Result<(), String> {
+        if self.eat(symbol) { Ok(()) } else { Err(format!("expected `{}`", symbol)) }
+    }
+
+    fn node(&mut self) -> Result<(), String> {
+        self.nodes += 1;
+        if self.nodes > MAX_NODES {
+            return Err(format!("the expression is too large (more than {} nodes)", MAX_NODES));
+        }
+        Ok(())
+    }
+
+    fn statements(&mut self, end: Option<&'static str>) -> Result<Vec<Statement>, String> {
+        let mut statements = Vec::new();
+        loop {
+            match (self.peek(), end) {
+                (None, None) => return Ok(statements),
+                (None, Some(end)) => return Err(format!("expected `{}`", end)),
+                (Some(Token::Symbol(symbol)), Some(end)) if *symbol == end => return Ok(statements),
+                _ => {},
+            }
+            statements.push(self.statement()?);
+            let closed = match self.peek() {
+                None => true,
+                Some(Token::Symbol(symbol)) => Some(*symbol) == end,
+                _ => false,
+            };
+            if !self.eat(";") && !closed && !matches!(statements.last(), Some(Statement::Expr(Expr::If(..)))) {
+                return Err("expected `;`".to_string());
+            }
+        }
+    }
+
+    fn statement(&mut self) -> Result<Statement, String> {
+        self.node()?;
+        if self.peek() == Some(&Token::Ident("let".to_string())) {
+            self.cursor += 1;
+            let name = match self.next() {
+                Some(Token::Ident(name)) if !VARIABLES.contains(&name.as_str()) => name,
+                _ => return Err("expected a variable name after `let`".to_string()),
+            };
+            self.expect("=")?;
+            let expr = self.expr()?;
+            self.names.insert(name.clone());
+            return Ok(Statement::Let(name, expr));
+        }
+        Ok(Statement::Expr(self.expr()?))
+    }
+
+    fn expr(&mut self) -> Result<Expr, String> {
+        self.node()?;
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(format!("the expression is too deep (more than {} levels)", MAX_DEPTH));
+        }
+        let expr = self.binary(0);
+        self.depth -= 1;
+        expr
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        const LEVELS: [&[&str]; 5] = [&["||"], &["&&"], &["<", "<=", ">", ">=", "==", "!="], &["+", "-"], &["*", "/", "%"]];
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        while let Some(Token::Symbol(symbol)) = self.peek() {
+            let symbol = *symbol;
+            if !LEVELS[level].contains(&symbol) {
+                break;
+            }
+            self.cursor += 1;
+            self.node()?;
+            let right = self.binary(level + 1)?;
+            left = Expr::Binary(symbol, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        for symbol in ["-", "!"] {
+            if self.eat(symbol) {
+                self.node()?;
+                return Ok(Expr::Unary(symbol, Box::new(self.unary()?)));
+            }
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        self.node()?;
+        match self.next() {
+            Some(Token::Number(number)) => Ok(Expr::Number(number)),
+            Some(Token::Text(text)) => Ok(Expr::Text(text)),
+            Some(Token::Symbol("(")) => {
+                let expr = self.expr()?;
+                self.expect(")")?;
+                Ok(expr)
+            },
+            Some(Token::Ident(name)) => match name.as_str() {
+                "true" => Ok(Expr::Number(1.0)),
+                "false" => Ok(Expr::Number(0.0)),
+                "if" => self.if_expr(),
+                _ if self.eat("(") => self.call(name),
+                _ if VARIABLES.contains(&name.as_str()) || self.names.contains(&name) => Ok(Expr::Variable(name)),
+                _ => Err(format!("unknown variable `{}`", name)),
+            },
+            Some(token) => Err(format!("unexpected token {:?}", token)),
+            None => Err("unexpected end of the expression".to_string()),
+        }
+    }
+
+    fn if_expr(&mut self) -> Result<Expr, String> {
+        let condition = self.expr()?;
+        self.expect("{")?;
+        let then = self.statements(Some("}"))?;
+        self.expect("}")?;
+        let otherwise = if self.peek() == Some(&Token::Ident("else".to_string())) {
+            self.cursor += 1;
+            if self.peek() == Some(&Token::Ident("if".to_string())) {
+                self.cursor += 1;
+                Some(vec![Statement::Expr(self.if_expr()?)])
+            } else {
+                self.expect("{")?;
+                let otherwise = self.statements(Some("}"))?;
+                self.expect("}")?;
+                Some(otherwise)
+            }
+        } else {
+            None
+        };
+        Ok(Expr::If(Box::new(condition), then, otherwise))
+    }
+
+    fn call(&mut self, name: String) -> Result<Expr, String> {
+        let mut args = Vec::new();
+        if !self.eat(")") {
+            loop {
+                args.push(self.expr()?);
+                if self.eat(")") {
+                    break;
+                }
+                self.expect(",")?;
+            }
+        }
+        let arity: &[usize] = match name.as_str() {
+            "emit" | "abs" | "sqrt" | "round" | "floor" | "ceil" => &[1],
+            "min" | "max" => &[2],
+            "clamp" => &[3],
+            "latest" => &[1, 2],
+            _ => return Err(format!("unknown function `{}`", name)),
+        };
+        if !arity.contains(&args.len()) {
+            return Err(format!("wrong number of arguments for `{}`", name));
+        }
+        match (name.as_str(), args.first()) {
+            ("latest", Some(Expr::Text(topic))) => self.topics.push(topic.clone()),
+            ("latest", _) => return Err("the topic of `latest` must be a string".to_string()),
+            ("emit", _) => self.emits = true,
+            _ => {},
+        }
+        Ok(Expr::Call(name, args))
+    }
+}
+
+/// The inputs of an evaluation.
+pub struct Context<'a> {
+    pub value: f64,
+    pub prev: f64,
+    pub count: u64,
+    pub latest: &'a HashMap<String, f32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Number(f64),
+    Text(String),
+    Nothing,
+}
+
+struct Evaluation<'a> {
+    context: &'a Context<'a>,
+    variables: HashMap<String, f64>,
+    steps: usize,
+    emitted: Vec<f64>,
+}
+
+impl<'a> Evaluation<'a> {
+    fn statements(&mut self, statements: &[Statement]) -> Result<Value, String> {
+        let mut last = Value::Nothing;
+        for statement in statements {
+            last = match statement {
+                Statement::Let(name, expr) => {
+                    let value = self.number(expr)?;
+                    self.variables.insert(name.clone(), value);
+                    Value::Nothing
+                },
+                Statement::Expr(expr) => self.eval(expr)?,
+            };
+        }
+        Ok(last)
+    }
+
+    fn number(&mut self, expr: &Expr) -> Result<f64, String> {
+        match self.eval(expr)? {
+            Value::Number(number) => Ok(number),
+            Value::Text(_) => Err("expected a number, found a string".to_string()),
+            Value::Nothing => Err("expected a number, found nothing".to_string()),
+        }
+    }
+
+    fn eval(&mut self, expr: &Expr) -> Result<Value, String> {
+        self.steps += 1;
+        if self.steps > MAX_STEPS {
+            return Err(format!("the evaluation exceeded {} steps", MAX_STEPS));
+        }
+        let number = match expr {
+            Expr::Number(number) => *number,
+            Expr::Text(text) => return Ok(Value::Text(text.clone())),
+            Expr::Variable(name) => match name.as_str() {
+                "value" => self.context.value,
+                "prev" => self.context.prev,
+                "count" => self.context.count as f64,
+                _ => *self.variables.get(name).ok_or(format!("`{}` is not defined yet", name))?,
+            },
+            Expr::Unary(op, operand) => {
+                let operand = self.number(operand)?;
+                if *op == "-" { -operand } else { truth(operand == 0.0) }
+            },
+            Expr::Binary(op, left, right) => {
+                let left = self.number(left)?;
+                // && and || short-circuit like the other languages.
+                match (*op, left != 0.0) {
+                    ("&&", false) => return Ok(Value::Number(0.0)),
+                    ("||", true) => return Ok(Value::Number(1.0)),
+                    _ => {},
+                }
+                let right = self.number(right)?;
+                match *op {
+                    "+" => left + right,
+                    "-" => left - right,
+                    "*" => left * right,
+                    "/" => left / right,
+                    "%" => left % right,
+                    "<" => truth(left < right),
+                    "<=" => truth(left <= right),
+                    ">" => truth(left > right),
+                    ">=" => truth(left >= right),
+                    "==" => truth(left == right),
+                    "!=" => truth(left != right),
+                    _ => truth(right != 0.0), // The left side of && is true, or the left side of || is false.
+                }
+            },
+            Expr::If(condition, then, otherwise) => {
+                return match (self.number(condition)? != 0.0, otherwise) {
+                    (true, _) => self.statements(then),
+                    (false, Some(otherwise)) => self.statements(otherwise),
+                    (false, None) => Ok(Value::Nothing),
+                };
+            },
+            Expr::Call(name, args) => return self.call(name, args),
+        };
+        Ok(Value::Number(number))
+    }
+
+    fn call(&mut self, name: &str, args: &[Expr]) -> Result<Value, String> {
+        if name == "latest" {
+            let topic = match self.eval(&args[0])? {
+                Value::Text(topic) => topic,
+                _ => return Err("the topic of `latest` must be a string".to_string()),
+            };
+            return match (self.context.latest.get(&topic), args.get(1)) {
+                (Some(value), _) => Ok(Value::Number(*value as f64)),
+                (None, Some(default)) => Ok(Value::Number(self.number(default)?)),
+                (None, None) => Err(format!("no value has been received from `{}`", topic)),
+            };
+        }
+        let mut values = Vec::with_capacity(args.len());
+        for arg in args {
+            values.push(self.number(arg)?);
+        }
+        let number = match name {
+            "emit" => {
+                if !values[0].is_finite() {
+                    return Err(format!("cannot emit {}", values[0]));
+                }
+                if self.emitted.len() == MAX_EMITS {
+                    return Err(format!("cannot emit more than {} values", MAX_EMITS));
+                }
+                self.emitted.push(values[0]);
+                return Ok(Value::Nothing);
+            },
+            "abs" => values[0].abs(),
+            "sqrt" => values[0].sqrt(),
+            "round" => values[0].round(),
+            "floor" => values[0].floor(),
+            "ceil" => values[0].ceil(),
+            "min" => values[0].min(values[1]),
+            "max" => values[0].max(values[1]),
+            _ => values[0].max(values[1]).min(values[2]), // clamp
+        };
+        Ok(Value::Number(number))
+    }
+}
+
+fn truth(condition: bool) -> f64 {
+    if condition { 1.0 } else { 0.0 }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    source: String,
+    statements: Vec<Statement>,
+    emits: bool,
+    topics: Vec<String>,
+}
+
+impl Program {
+    pub fn compile(source: &str) -> Result<Program, String> {
+        if source.len() > MAX_SOURCE_LENGTH {
+            return Err(format!("the expression is too long (more than {} bytes)", MAX_SOURCE_LENGTH));
+        }
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            cursor: 0,
+            nodes: 0,
+            depth: 0,
+            names: HashSet::new(),
+            emits: false,
+            topics: Vec::new(),
+        };
+        let statements = parser.statements(None)?;
+        if statements.is_empty() {
+            return Err("the expression is empty".to_string());
+        }
+        Ok(Program {
+            source: source.to_string(),
+            statements,
+            emits: parser.emits,
+            topics: parser.topics,
+        })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// The topics which are read by `latest`.
+    pub fn topics(&self) -> &[String] {
+        &self.topics
+    }
+
+    /// Evaluates the program and returns the emitted values.
+    pub fn evaluate(&self, context: &Context) -> Result<Vec<f64>, String> {
+        let mut evaluation = Evaluation {
+            context,
+            variables: HashMap::new(),
+            steps: 0,
+            emitted: Vec::new(),
+        };
+        let last = evaluation.statements(&self.statements)?;
+        match (self.emits, last) {
+            (false, Value::Number(number)) if number.is_finite() => Ok(vec![number]),
+            (false, Value::Number(number)) => Err(format!("cannot emit {}", number)),
+            _ => Ok(evaluation.emitted),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn evaluate(source: &str, value: f64, prev: f64) -> Result<Vec<f64>, String> {
+        let latest = HashMap::from([("i483-sensors-s2420010-SCD41-temperature".to_string(), 24.0)]);
+        Program::compile(source)?.evaluate(&Context { value, prev, count: 1, latest: &latest })
+    }
+
+    #[test]
+    fn test_evaluate_expression() {
+        assert_eq!(evaluate("value * 0.01", 101325.0, 0.0), Ok(vec![1013.25]));
+        assert_eq!(evaluate("(value - 32) * 5 / 9", 212.0, 0.0), Ok(vec![100.0]));
+        assert_eq!(evaluate("let d = value - prev; abs(d)", 10.0, 15.0), Ok(vec![5.0]));
+        assert_eq!(evaluate("value - latest(\"i483-sensors-s2420010-SCD41-temperature\")", 25.5, 0.0), Ok(vec![1.5]));
+        assert_eq!(evaluate("latest(\"unknown\", -1)", 0.0, 0.0), Ok(vec![-1.0]));
+        assert_eq!(evaluate("if value > 10 { 1 } else if value > 5 { 2 } else { 3 }", 7.0, 0.0), Ok(vec![2.0]));
+    }
+
+    #[test]
+    fn test_evaluate_filter() {
+        let source = "if value > prev + 50 { emit(value) }";
+        assert_eq!(evaluate(source, 900.0, 800.0), Ok(vec![900.0]));
+        assert_eq!(evaluate(source, 820.0, 800.0), Ok(vec![]));
+        assert_eq!(evaluate("emit(value); emit(value * 2)", 1.0, 0.0), Ok(vec![1.0, 2.0]));
+        assert_eq!(evaluate("if value > 10 { value }", 1.0, 0.0), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        assert!(Program::compile("value *").is_err());
+        assert!(Program::compile("unknown + 1").is_err());
+        assert!(Program::compile("exec(\"rm\")").is_err());
+        assert!(Program::compile("max(value)").is_err());
+        assert!(Program::compile("latest(value)").is_err());
+        assert!(Program::compile("let value = 1").is_err());
+        assert!(Program::compile("").is_err());
+    }
+
+    #[test]
+    fn test_resource_limits() {
+        assert!(Program::compile(&format!("{}value", "value + ".repeat(200))).is_err());
+        assert!(Program::compile(&format!("{}value{}", "(".repeat(40), ")".repeat(40))).is_err());
+        assert!(evaluate(&"emit(value);".repeat(17), 1.0, 0.0).is_err());
+        assert!(evaluate("value / 0", 1.0, 0.0).is_err());
+        assert!(evaluate("latest(\"unknown\")", 1.0, 0.0).is_err());
+    }
+}
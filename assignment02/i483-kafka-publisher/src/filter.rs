@@ -0,0 +1,95 @@
+/*
+    This is the filter module. It removes the single-sample spikes with a causal Hampel filter:
+    a sample which is further than n_sigmas * 1.4826 * MAD from the median of the previous samples
+    is replaced with the median. (1.4826 * MAD estimates the standard deviation of a normal distribution.)
+
+    The window keeps the original samples, so a real step change is accepted once it fills half of the window.
+    The window of the constant samples (MAD = 0) replaces nothing, otherwise any change would be a spike.
+*/
+use std::collections::VecDeque;
+
+
+const MIN_WINDOW: usize = 3;
+const MAD_SCALE: f64 = 1.4826;
+
+pub struct HampelFilter {
+    window: usize,
+    n_sigmas: f64,
+    history: VecDeque<f64>,
+}
+
+fn median(values: &mut [f64]) -> f64 {
+    values.sort_by(|a, b| a.total_cmp(b));
+    let middle = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        (values[middle - 1] + values[middle]) / 2.0
+    } else {
+        values[middle]
+    }
+}
+
+impl HampelFilter {
+    pub fn new(window: usize, n_sigmas: f64) -> HampelFilter {
+        HampelFilter {
+            window: window.max(MIN_WINDOW),
+            n_sigmas,
+            history: VecDeque::new(),
+        }
+    }
+
+    /// Returns the cleaned value, and whether the value is replaced.
+    pub fn filter(&mut self, value: f64) -> (f64, bool) {
+        let result = if self.history.len() < self.window {
+            (value, false)
+        } else {
+            let mut values: Vec<f64> = self.history.iter().copied().collect();
+            let center = median(&mut values);
+            let mut deviations: Vec<f64> = values.iter().map(|v| (v - center).abs()).collect();
+            let mad = median(&mut deviations);
+            if mad > 0.0 && (value - center).abs() > self.n_sigmas * MAD_SCALE * mad {
+                (center, true)
+            } else {
+                (value, false)
+            }
+        };
+        self.history.push_back(value);
+        if self.history.len() > self.window {
+            self.history.pop_front();
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replace_spike() {
+        let mut filter = HampelFilter::new(5, 3.0);
+        for value in [812.0, 815.0, 810.0, 818.0, 813.0] {
+            assert_eq!(filter.filter(value), (value, false));
+        }
+        assert_eq!(filter.filter(5000.0), (813.0, true));
+        assert_eq!(filter.filter(816.0), (816.0, false));
+    }
+
+    #[test]
+    fn test_accept_step_change() {
+        let mut filter = HampelFilter::new(5, 3.0);
+        for value in [20.0, 20.1, 19.9, 20.0, 20.2] {
+            filter.filter(value);
+        }
+        let replaced: Vec<bool> = [25.0, 25.1, 24.9, 25.0].iter().map(|value| filter.filter(*value).1).collect();
+        assert_eq!(replaced, vec![true, true, true, false]);
+    }
+
+    #[test]
+    fn test_constant_window_replaces_nothing() {
+        let mut filter = HampelFilter::new(3, 3.0);
+        for _ in 0..3 {
+            filter.filter(23.4);
+        }
+        assert_eq!(filter.filter(23.5), (23.5, false));
+    }
+}
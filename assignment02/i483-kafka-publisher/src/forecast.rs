@@ -0,0 +1,91 @@
+/*
+    This is the forecast module. It fits a linear trend (least squares) to the samples of a window
+    and extrapolates it to predict the value ahead of the latest sample.
+*/
+use std::collections::VecDeque;
+use chrono::{DateTime, Utc};
+
+
+const MIN_SAMPLES: usize = 3;
+
+pub struct LinearTrend {
+    window: i64, // seconds
+    samples: VecDeque<(DateTime<Utc>, f64)>,
+}
+
+impl LinearTrend {
+    pub fn new(window: u64) -> LinearTrend {
+        LinearTrend {
+            window: window as i64,
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Adds a sample and drops the samples which are older than the window.
+    pub fn add(&mut self, time: DateTime<Utc>, value: f64) {
+        self.samples.push_back((time, value));
+        while let Some((oldest, _)) = self.samples.front() {
+            if (time - *oldest).num_seconds() <= self.window {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+
+    /// The slope (per second) and the intercept at the latest sample, or None with too few samples.
+    fn fit(&self) -> Option<(f64, f64)> {
+        let (latest, _) = self.samples.back()?;
+        if self.samples.len() < MIN_SAMPLES {
+            return None;
+        }
+        let points: Vec<(f64, f64)> = self.samples.iter()
+            .map(|(time, value)| ((*time - *latest).num_milliseconds() as f64 / 1000.0, *value))
+            .collect();
+        let n = points.len() as f64;
+        let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+        let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+        let covariance: f64 = points.iter().map(|(x, y)| (x - mean_x) * (y - mean_y)).sum();
+        let variance: f64 = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+        if variance == 0.0 {
+            return None;
+        }
+        let slope = covariance / variance;
+        Some((slope, mean_y - slope * mean_x))
+    }
+
+    /// The predicted value `horizon` seconds after the latest sample.
+    pub fn predict(&self, horizon: u64) -> Option<f64> {
+        let (slope, intercept) = self.fit()?;
+        Some(intercept + slope * horizon as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn test_predict_linear_trend() {
+        let start = Utc::now();
+        let mut trend = LinearTrend::new(600);
+        trend.add(start, 800.0);
+        trend.add(start + Duration::seconds(15), 805.0);
+        assert_eq!(trend.predict(600), None);
+        trend.add(start + Duration::seconds(30), 810.0);
+        let predicted = trend.predict(600).unwrap();
+        assert!((predicted - 1010.0).abs() < 1e-6, "{}", predicted);
+    }
+
+    #[test]
+    fn test_window_drops_old_samples() {
+        let start = Utc::now();
+        let mut trend = LinearTrend::new(60);
+        trend.add(start, 2000.0);
+        for i in 1..=4 {
+            trend.add(start + Duration::seconds(60 + i * 15), 800.0);
+        }
+        assert_eq!(trend.samples.len(), 4);
+        assert_eq!(trend.predict(600), Some(800.0));
+    }
+}
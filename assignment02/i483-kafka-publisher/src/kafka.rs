@@ -7,15 +7,28 @@ use rdkafka::config::{ClientConfig, RDKafkaLogLevel};
 use rdkafka::consumer::stream_consumer::StreamConsumer;
 use rdkafka::consumer::{BaseConsumer, CommitMode, Consumer, ConsumerContext, Rebalance};
 use rdkafka::error::KafkaResult;
-use rdkafka::message::{Headers, Message, OwnedMessage};
+use rdkafka::message::{Header, Headers, Message, OwnedHeaders, OwnedMessage};
 use rdkafka::producer::{FutureProducer, FutureRecord};
+use regex::Regex;
 use tokio::sync::{mpsc, Mutex};
+use tokio::sync::mpsc::Sender;
 use std::sync::Arc;
 use rdkafka::util::Timeout;
 use uuid::Uuid;
-use crate::cli::ProcessType;
+use i483_sensors::SensorTopic;
+use crate::alert::AlertSink;
+use crate::cli::{Args, InputFormat, OutputFormat, ProcessType, ValidationPolicy};
+use crate::output::encode_payload;
+use crate::schema::{self, SchemaRegistry, SchemaType};
+use crate::expression::Program;
+use crate::ordering::Ordering;
+use crate::transform::Transformer;
+use crate::validation::{Validator, Violation};
 
-use crate::worker::{ActorMessage, ComputeActor, create_actor, ProcessData};
+use crate::worker::{ActorMessage, ComputeActor, create_actor, ProcessData, SourceRecord, WindowInfo};
+
+// milliseconds, added to the reorder tolerance for the age cutoff.
+const AGE_MARGIN: u64 = 1000;
 
 
 fn create_consumer_config(group_id: &str, client_id: &str) -> ClientConfig {
@@ -30,26 +43,66 @@ fn create_consumer_config(group_id: &str, client_id: &str) -> ClientConfig {
     client_config
 }
 
-fn parse_kafka_payload(payload: Option<&[u8]>) -> f32 {
-    return match payload {
-        None => 0.0,
-        Some(payload) => f32::from_str(&String::from_utf8_lossy(payload).to_string()).unwrap_or(0.0),
+// An empty or undecodable payload is NaN, which the validation rejects as not a number.
+// The cutoff of the message age. The readings which are held by the ordering stage for the tolerance are older than it
+// when they are released, so the cutoff is at least the tolerance and the margin.
+fn max_age(args: &Args) -> Option<Duration> {
+    match args.max_age {
+        0 => None,
+        max_age if args.reorder_tolerance > 0 => Some(Duration::milliseconds(max_age.max(args.reorder_tolerance + AGE_MARGIN) as i64)),
+        max_age => Some(Duration::milliseconds(max_age as i64)),
     }
 }
 
+async fn parse_kafka_payload(payload: Option<&[u8]>, input_format: &InputFormat, registry: &SchemaRegistry) -> f32 {
+    let schema_type = match input_format {
+        InputFormat::Raw => {
+            return match payload {
+                None => f32::NAN,
+                Some(payload) => f32::from_str(String::from_utf8_lossy(payload).trim()).unwrap_or(f32::NAN),
+            }
+        },
+        InputFormat::Avro => SchemaType::Avro,
+        InputFormat::Protobuf => SchemaType::Protobuf,
+    };
+    match payload {
+        None => f32::NAN,
+        Some(payload) => schema::decode_value(schema_type, registry, payload).await.unwrap_or_else(|e| {
+            println!("Error decoding payload: {:?}", e);
+            f32::NAN
+        }),
+    }
+}
+
+fn header_value(message: &OwnedMessage, key: &str) -> Option<String> {
+    message.headers()?.iter()
+        .find(|header| header.key == key)
+        .and_then(|header| header.value)
+        .map(|value| String::from_utf8_lossy(value).to_string())
+}
+
 fn debug_kafka_message(message: &OwnedMessage) {
+    let headers: Vec<String> = match message.headers() {
+        Some(headers) => headers.iter()
+            .map(|header| format!("{}={}", header.key, header.value.map(String::from_utf8_lossy).unwrap_or_default()))
+            .collect(),
+        None => Vec::new(),
+    };
     println!("Received message from topic: {}, partition: {}, offset: {}, timestamp: {:?}, key: {:?}, payload: {:?}, headers: {:?}",
         message.topic(),
         message.partition(),
         message.offset(),
         message.timestamp(),
-        message.key(),
+        message.key().map(String::from_utf8_lossy),
         message.payload(),
-        message.headers(),
+        headers,
     );
 }
 
-pub async fn listen(host: &str, topics: &Vec<String>, debug: &bool) -> Result<(), rdkafka::error::KafkaError> {
+pub async fn listen(args: &Args) -> Result<(), rdkafka::error::KafkaError> {
+    let (host, topics, debug) = (&args.host, &args.topics, &args.debug);
+    let max_age = max_age(args);
+    let registry = Arc::new(SchemaRegistry::new(&args.schema_registry));
     let mut config = create_consumer_config("kafka-cli", "kafka-cli");
     let consumer: StreamConsumer = config.set("bootstrap.servers", host).create().unwrap();
     let topics_for_consume: Vec<&str> = topics.iter().map(AsRef::as_ref).collect();
@@ -57,20 +110,21 @@ pub async fn listen(host: &str, topics: &Vec<String>, debug: &bool) -> Result<()
     loop {
         match consumer.stream().try_for_each(|borrowed_message| {
             let debug = debug.clone();
+            let input_format = args.input_format.clone();
+            let registry = registry.clone();
             async move {
                 borrowed_message.offset();
                 let owned_message = borrowed_message.detach();
                 tokio::spawn(async move {
                     let data_timestamp = DateTime::from_timestamp_millis(owned_message.timestamp().to_millis().unwrap()).unwrap();
-                    let acceptable_timestamp = Utc::now() - Duration::seconds(3);
-                    if data_timestamp < acceptable_timestamp {
+                    if max_age.is_some_and(|max_age| data_timestamp < Utc::now() - max_age) {
                         println!("Message is too old, skipping");
                         return;
                     }
                     if debug {
                         debug_kafka_message(&owned_message);
                     }
-                    println!("Received message from topic: {}, value: {}", owned_message.topic(), parse_kafka_payload(owned_message.payload()));
+                    println!("Received message from topic: {}, value: {}", owned_message.topic(), parse_kafka_payload(owned_message.payload(), &input_format, &registry).await);
                 });
                 Ok(())
             }
@@ -87,139 +141,357 @@ pub async fn listen(host: &str, topics: &Vec<String>, debug: &bool) -> Result<()
     Ok(())
 }
 
-fn pairing_topics_and_processes(topics: &Vec<String>, processes: &Vec<ProcessType>) -> HashMap<String, ProcessType> {
-    let mut map = HashMap::new();
-    for (i, topic) in topics.iter().enumerate() {
-        map.insert(topic.clone(), processes.get(i).unwrap().clone());
+#[derive(Debug, Clone)]
+struct Route {
+    pattern: String,
+    regex: Option<Regex>,
+    process: ProcessType,
+}
+
+impl Route {
+    fn matches(&self, topic: &str) -> bool {
+        match &self.regex {
+            Some(regex) => regex.is_match(topic),
+            None => self.pattern == topic,
+        }
+    }
+
+    // The key of the state. The entity watchdog shares the state among the topics of an entity,
+    // and the consistency check among the sensors of the same data type on an entity.
+    fn state_key(&self, topic: &str) -> String {
+        match (&self.process, SensorTopic::parse(topic)) {
+            (ProcessType::Watchdog { per_entity: true, .. }, Some(sensor_topic)) => sensor_topic.entity,
+            (ProcessType::Consistency { .. }, Some(sensor_topic)) => format!("{}-{}", sensor_topic.entity, sensor_topic.data_type),
+            _ => topic.to_string(),
+        }
+    }
+}
+
+// The topics which start with `^` are regex patterns, same as the subscription of librdkafka.
+// The patterns and the counts are checked by cli::parse_args.
+fn pairing_topics_and_processes(topics: &[String], processes: &[ProcessType]) -> Vec<Route> {
+    topics.iter().zip(processes.iter()).map(|(topic, process)| Route {
+        pattern: topic.clone(),
+        regex: topic.starts_with('^').then(|| Regex::new(topic).expect("the pattern is checked by the cli")),
+        process: process.clone(),
+    }).collect()
+}
+
+// The state of a process is kept per source topic (i.e. per entity of a pattern).
+// It is created on the first message and evicted after the idle timeout.
+struct KeyedActor {
+    id: Uuid,
+    sender: Sender<ActorMessage>,
+    topic: String,
+    process: ProcessType,
+    last_seen: DateTime<Utc>,
+}
+
+type KeyedActors = Arc<Mutex<HashMap<(String, String), KeyedActor>>>;
+
+fn spawn_keyed_actor(process: &ProcessType, topic: &str, tx: &Sender<ActorMessage>) -> KeyedActor {
+    let lifespan = match process {
+        ProcessType::RollingAverage(_) => 30,
+        ProcessType::Threshold(_) | ProcessType::Watchdog { .. } | ProcessType::Percentile { .. } | ProcessType::Forecast { .. } | ProcessType::Hampel { .. } | ProcessType::Consistency { .. } | ProcessType::Downsample { .. } | ProcessType::Expression { .. } => 0,
+    };
+    let (id, sender) = create_actor(lifespan, process.clone(), tx);
+    match SensorTopic::parse(topic) {
+        Some(sensor_topic) => println!("Created actor {} for entity {} ({})", id, sensor_topic.entity, topic),
+        None => println!("Created actor {} for {}", id, topic),
+    }
+    KeyedActor {
+        id,
+        sender,
+        topic: topic.to_string(),
+        process: process.clone(),
+        last_seen: Utc::now(),
+    }
+}
+
+// A valid reading on the way to the processes.
+struct Reading {
+    topic: String,
+    payload: f32,
+    source: SourceRecord,
+}
+
+#[derive(Clone)]
+struct Router {
+    routes: Arc<Vec<Route>>,
+    actors: KeyedActors,
+    tx: Sender<ActorMessage>,
+    latest: Arc<Mutex<HashMap<String, f32>>>,
+}
+
+impl Router {
+    // Feeds the reading to the actors of the matching routes, creating them on the first reading.
+    async fn route(&self, reading: Reading) {
+        let Reading { topic, payload, source } = reading;
+        self.latest.lock().await.insert(topic.clone(), payload);
+        for route in self.routes.iter().filter(|route| route.matches(&topic)) {
+            let sender = {
+                let mut lock = self.actors.lock().await;
+                let actor = lock.entry((route.pattern.clone(), route.state_key(&topic)))
+                    .or_insert_with(|| spawn_keyed_actor(&route.process, &topic, &self.tx));
+                actor.last_seen = Utc::now();
+                actor.sender.clone()
+            };
+            let data = match &route.process {
+                // Only the values of the topics which are read by `latest` of the program are passed to the actor.
+                ProcessType::Expression { program, .. } => {
+                    let latest = self.latest.lock().await;
+                    let values = program.topics().iter()
+                        .filter_map(|topic| latest.get(topic).map(|value| (topic.clone(), *value)))
+                        .collect();
+                    ProcessData::Expression(payload, values)
+                },
+                _ => ProcessData::new(&route.process, payload),
+            };
+            if let Err(e) = sender.send(ActorMessage::FeedData(Uuid::default(), data, source.clone())).await {
+                println!("Error sending message to actor: {:?}", e);
+            }
+        }
+    }
+}
+
+struct Emitter {
+    producer: FutureProducer,
+    output_format: OutputFormat,
+    registry: Arc<SchemaRegistry>,
+    pipeline_id: String,
+    debug: bool,
+    dry_run: bool,
+    alerts: Option<AlertSink>,
+}
+
+impl Emitter {
+    async fn emit(&self, process: &ProcessType, source_topic: &str, data: ProcessData, window: &WindowInfo) {
+        let statistics = data.statistics().cloned();
+        let is_alert = self.alerts.as_ref().is_some_and(|alerts| alerts.is_alert(process, &data));
+        let (topic, payload) = generate_payload(process.clone(), data, source_topic, self.debug);
+        if payload.is_empty() {
+            return;
+        }
+        if let Some(alerts) = self.alerts.as_ref().filter(|_| is_alert) {
+            alerts.publish(&topic, source_topic, &payload, self.dry_run).await;
+        }
+        let payload = match encode_payload(&self.output_format, &self.registry, &topic, process, source_topic, &payload, statistics.as_ref(), window).await {
+            Ok(payload) => payload,
+            Err(e) => {
+                println!("Error encoding payload: {:?}", e);
+                return;
+            }
+        };
+        if !self.dry_run {
+            produce(self.producer.clone(), &topic, &record_key(source_topic), record_headers(window, &self.pipeline_id), &payload).await.unwrap();
+        }
     }
-    return map;
 }
 
-pub async fn process(host: &str, topics: &Vec<String>, processes: &Vec<ProcessType>, debug: &bool, dry_run: &bool) {
+pub async fn process(args: &Args) {
+    let (host, topics, processes, debug) = (&args.host, &args.topics, &args.processes, args.debug);
+    let max_age = max_age(args);
+    let registry = Arc::new(SchemaRegistry::new(&args.schema_registry));
     let mut config = create_consumer_config("kafka-cli", "kafka-cli");
     let consumer: StreamConsumer = config.set("bootstrap.servers", host).create().unwrap();
     let producer: FutureProducer = ClientConfig::new().set("bootstrap.servers", host).create().unwrap();
-    let topics_for_consume: Vec<&str> = topics.iter().map(AsRef::as_ref).collect();
-    let topics_and_processes = pairing_topics_and_processes(topics, processes);
-    let (tx, mut rx) = mpsc::channel::<ActorMessage>(100);
-    let mut actors = Arc::new(Mutex::new(HashMap::new()));
-    let mut last_rolling_average_actor_crated = Utc::now();
-    consumer.subscribe(&topics_for_consume).unwrap();
-    for (t, p) in &topics_and_processes {
-        match p {
-            ProcessType::Threshold(value) => {
-                let (actor_id, sender) = create_actor(0, ProcessType::Threshold(value.clone()), &tx);
-                let actors = actors.clone();
-                tokio::spawn(async move {
-                    let mut lock = actors.lock().await;
-                    lock.insert(actor_id, sender);
-                });
-            }
-            ProcessType::RollingAverage(value) => {
-                let (actor_id, sender) = create_actor(30, ProcessType::RollingAverage(value.clone()), &tx);
-                let actors = actors.clone();
-                tokio::spawn(async move {
-                    let mut lock = actors.lock().await;
-                    lock.insert(actor_id, sender);
-                });
-            }
+    let mut topics_for_consume: Vec<&str> = topics.iter().map(AsRef::as_ref).collect();
+    // The topics which are read by the expressions are consumed too, only to keep their latest values.
+    let programs: Vec<&Program> = processes.iter().filter_map(|process| match process {
+        ProcessType::Expression { program, .. } => Some(program),
+        _ => None,
+    }).collect();
+    for topic in programs.iter().flat_map(|program| program.topics()) {
+        if !topics_for_consume.contains(&topic.as_str()) {
+            topics_for_consume.push(topic);
         }
     }
+    let routes = pairing_topics_and_processes(topics, processes);
+    let (tx, mut rx) = mpsc::channel::<ActorMessage>(100);
+    let actors: KeyedActors = Arc::new(Mutex::new(HashMap::new()));
+    consumer.subscribe(&topics_for_consume).unwrap();
+
     let receiver_runtime = tokio::runtime::Builder::new_multi_thread().worker_threads(1).enable_all().build().unwrap();
     let copied_actors = actors.clone();
-    let copied_tap = topics_and_processes.clone();
-    let debug = debug.clone();
-    let dry_run = dry_run.clone();
-    let future_producer = producer.clone();
+    let pipeline_id = args.pipeline_id.clone().unwrap_or(Uuid::new_v4().to_string());
+    println!("Pipeline id: {}", &pipeline_id);
+    let alerts = args.mqtt_broker.as_ref().map(|broker| {
+        let client_id = args.mqtt_client_id.clone().unwrap_or(format!("i483-kafka-alerts-{}", &pipeline_id));
+        AlertSink::start(broker, &client_id, &args.mqtt_alerts)
+    });
+    let emitter = Emitter {
+        producer: producer.clone(),
+        output_format: args.output_format.clone(),
+        registry: registry.clone(),
+        pipeline_id,
+        debug,
+        dry_run: args.dry_run,
+        alerts,
+    };
     receiver_runtime.spawn(async move {
         // https://stackoverflow.com/questions/77494743/tokio-mpsc-closes-channel-when-sender-assigned-to-static
-        loop {
-            if let Some(actor_message) = rx.recv().await {
-            match actor_message {
-                ActorMessage::Finished(uuid, data) => {
+        while let Some(actor_message) = rx.recv().await {
+            let (uuid, data, window) = match actor_message {
+                ActorMessage::Finished(uuid, data, window) => {
                     println!("Actor finished processing data: {:?}, from: {}", data, &uuid);
-                    let mut lock = copied_actors.lock().await;
-                    // lock.remove(&uuid);
-                    for (t, p) in &copied_tap {
-                        let (topic, payload) = generate_payload(p.clone(), data.clone(), t, debug);
-                        if payload.is_empty() {
-                            continue;
-                        }
-                        if !dry_run {
-                            produce(future_producer.clone(), &topic, &payload).await.unwrap();
-                        }
-                    }
+                    (uuid, data, window)
                 },
-                ActorMessage::Updated(uuid, data) => {
+                ActorMessage::Updated(uuid, data, window) => {
                     println!("Actor updated data: {:?}, from: {}", data, uuid);
-                    for (t, p) in &copied_tap {
-                        let (topic, payload) = generate_payload(p.clone(), data.clone(), t, debug);
-                        if payload.is_empty() {
-                            continue;
-                        }
-                        if !dry_run {
-                            produce(future_producer.clone(), &topic, &payload).await.unwrap();
-                        }
-                    }
+                    (uuid, data, window)
                 },
-                _ => {}
+                _ => continue,
+            };
+            let target = copied_actors.lock().await.values()
+                .find(|actor| actor.id == uuid)
+                .map(|actor| (actor.process.clone(), actor.topic.clone()));
+            match target {
+                Some((process, topic)) => emitter.emit(&process, &topic, data, &window).await,
+                None => println!("Actor {} is already evicted, dropping: {:?}", uuid, data),
+            }
+        }
+    });
+
+    let evicting_actors = actors.clone();
+    let idle_timeout = Duration::seconds(args.idle_timeout as i64);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(10));
+        loop {
+            interval.tick().await;
+            evicting_actors.lock().await.retain(|_, actor| {
+                // A timed actor is kept until its last emit after the last reading, and the tick which makes it.
+                let final_emit = Duration::seconds(actor.process.final_emit_after() as i64 + 10);
+                let is_active = Utc::now() - actor.last_seen < idle_timeout.max(final_emit);
+                if !is_active {
+                    println!("Evicting actor {} of {} after inactivity", actor.id, actor.topic);
                 }
+                is_active
+            });
+        }
+    });
+
+    let validator = Arc::new(Mutex::new(Validator::new(args.stuck_after)));
+    let transformer = Arc::new(Transformer::new(&args.calibrations, &args.conversions));
+    let ordering: Arc<Mutex<Ordering<Reading>>> = Arc::new(Mutex::new(Ordering::new(args.dedup_horizon, args.dedup_by.clone(), args.reorder_tolerance)));
+    let router = Router {
+        routes: Arc::new(routes),
+        actors: actors.clone(),
+        tx: tx.clone(),
+        latest: Arc::new(Mutex::new(HashMap::new())),
+    };
+
+    let ticking_actors = actors.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            for actor in ticking_actors.lock().await.values().filter(|actor| actor.process.is_timed()) {
+                let _ = actor.sender.try_send(ActorMessage::Tick);
             }
         }
     });
 
-    // let copied_actors = actors.clone();
-    // let copied_tap = topics_and_processes.clone();
-    let copied_tap2 = topics_and_processes.clone();
-    loop {
-        // tokio::spawn(async move {
-        //     let tap = copied_tap.clone();
-        //     let mut lock = copied_actors.lock().await;
-        //     if lock.len() == 1 || Utc::now() - last_rolling_average_actor_crated > Duration::seconds(30) {
-        //         println!("Creating new rolling average actor");
-        //         for (t, p) in &tap {
-        //             match p {
-        //                 ProcessType::RollingAverage(value) => {
-        //                     let (actor_id, sender) = create_actor(value.clone(), ProcessType::RollingAverage(value.clone()), &tx);
-        //                     last_rolling_average_actor_crated = Utc::now();
-        //                     lock.insert(actor_id, sender);
-        //                 }
-        //                 _ => {}
-        //             }
-        //         }
-        //     }
-        // });
+    // The released readings are routed by one task in the order of the queue. They are queued while the ordering is locked,
+    // without waiting, so the order is kept and the lock is not held while routing.
+    let (released, mut routing) = mpsc::unbounded_channel::<Reading>();
+    let routing_router = router.clone();
+    tokio::spawn(async move {
+        while let Some(reading) = routing.recv().await {
+            routing_router.route(reading).await;
+        }
+    });
+
+    if ordering.lock().await.is_reordering() {
+        let releasing_ordering = ordering.clone();
+        let released = released.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_millis(100));
+            loop {
+                interval.tick().await;
+                let mut ordering = releasing_ordering.lock().await;
+                for reading in ordering.release(Utc::now()) {
+                    let _ = released.send(reading);
+                }
+            }
+        });
+    }
+    if ordering.lock().await.is_enabled() {
+        let reporting_ordering = ordering.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                println!("Ordering: {}", reporting_ordering.lock().await.stats);
+            }
+        });
+    }
 
+    loop {
         match consumer.stream().try_for_each(|borrowed_message| {
-            let copied_actors = actors.clone();
-            let debug = debug.clone();
-            let tap = copied_tap2.clone();
+            let released = released.clone();
+            let input_format = args.input_format.clone();
+            let registry = registry.clone();
+            let validator = validator.clone();
+            let transformer = transformer.clone();
+            let ordering = ordering.clone();
+            let validation = args.validation.clone();
+            let dead_letter_topic = args.dead_letter_topic.clone();
+            let dry_run = args.dry_run;
+            let producer = producer.clone();
             async move {
                 borrowed_message.offset();
                 let owned_message = borrowed_message.detach();
                 tokio::spawn(async move {
                     let data_timestamp = DateTime::from_timestamp_millis(owned_message.timestamp().to_millis().unwrap()).unwrap();
-                    let acceptable_timestamp = Utc::now() - Duration::seconds(3);
-                    if data_timestamp < acceptable_timestamp {
+                    if max_age.is_some_and(|max_age| data_timestamp < Utc::now() - max_age) {
                         println!("Message is too old, skipping");
                         return;
                     }
                     if debug {
                         debug_kafka_message(&owned_message);
                     }
-                    let payload = parse_kafka_payload(owned_message.payload());
-                    let process = tap.get(owned_message.topic()).unwrap();
-                    let mut actors_lock = copied_actors.lock().await;
-                    for (actor_id, sender) in actors_lock.iter() {
-                        match process {
-                            ProcessType::RollingAverage(_) => {
-                                sender.send(ActorMessage::FeedData(Uuid::default(), ProcessData::RollingAverage(payload))).await.unwrap();
+                    let topic = owned_message.topic().to_string();
+                    if ordering.lock().await.is_duplicate(&topic, owned_message.key(), data_timestamp, owned_message.payload(), Utc::now()) {
+                        println!("Duplicate message from topic: {}, skipping", &topic);
+                        return;
+                    }
+                    let payload = parse_kafka_payload(owned_message.payload(), &input_format, &registry).await;
+                    let (payload, unit) = transformer.apply(&topic, payload);
+                    let source = SourceRecord {
+                        topic: topic.clone(),
+                        partition: owned_message.partition(),
+                        offset: owned_message.offset(),
+                        timestamp: data_timestamp,
+                        correlation_id: header_value(&owned_message, "correlation-id"),
+                        unit: unit.clone(),
+                    };
+                    if let Some(policy) = &validation {
+                        if let Err(violation) = validator.lock().await.validate(&topic, payload, unit.as_deref()) {
+                            println!("Invalid reading from topic: {}, value: {}, {}", &topic, payload, &violation);
+                            let original = owned_message.payload().unwrap_or_default();
+                            let rejected_topic = match policy {
+                                ValidationPolicy::Drop => return,
+                                ValidationPolicy::Quarantine => format!("{}_invalid", &topic),
+                                ValidationPolicy::DeadLetter => dead_letter_topic,
+                            };
+                            if dry_run {
+                                return;
                             }
-                            ProcessType::Threshold(_) => {
-                                sender.send(ActorMessage::FeedData(Uuid::default(), ProcessData::Threshold(payload))).await.unwrap();
+                            if let Err(e) = produce(producer, &rejected_topic, &record_key(&topic), rejected_headers(&source, &violation), original).await {
+                                println!("Error republishing invalid reading from topic: {} to {}, {:?}", &topic, &rejected_topic, e);
                             }
+                            return;
                         }
                     }
+                    // Without the validation, a payload which is not a number is skipped instead of being fed to the processes.
+                    if !payload.is_finite() {
+                        println!("Payload from topic: {} is not a number, skipping", &topic);
+                        return;
+                    }
+                    let mut ordering = ordering.lock().await;
+                    for reading in ordering.push(data_timestamp, Reading { topic, payload, source }, Utc::now()) {
+                        let _ = released.send(reading);
+                    }
                 });
                 Ok(())
             }
@@ -235,39 +507,123 @@ pub async fn process(host: &str, topics: &Vec<String>, processes: &Vec<ProcessTy
     }
 }
 
-
 fn generate_payload(process: ProcessType, data: ProcessData, t: &str, debug: bool) -> (String, String) {
+    let debug_suffix = if debug { "-debug" } else { "" };
+    let sensor_topic = SensorTopic::parse(t);
     let (topic, payload) = match process {
         ProcessType::RollingAverage(_) => {
+            let topic = match &sensor_topic {
+                Some(sensor_topic) => sensor_topic.derived("_avg", debug_suffix),
+                None => format!("{}_avg{}", t, debug_suffix),
+            };
             match data {
-                ProcessData::RollingAverage(value) => {
-                    if debug {
-                        (t.replace("-temperature", "_avg-temperature-debug"), value.to_string())
-                    } else {
-                        (t.replace("-temperature", "_avg-temperature"), value.to_string())
-                    }
-                },
-                _ => {
-                    (t.replace("-temperature", "_avg-temperature"), "".to_string())
-                },
+                ProcessData::RollingAverage(value) => (topic, value.to_string()),
+                _ => (topic, "".to_string()),
             }
         }
         ProcessType::Threshold(_) => {
+            let topic = match &sensor_topic {
+                Some(sensor_topic) => sensor_topic.derived("", &format!("_threshold-crossed{}", debug_suffix)),
+                None => format!("{}_threshold-crossed{}", t, debug_suffix),
+            };
             match data {
                 ProcessData::Threshold(value) => {
                     let mut  threshold_result = "no";
                     if value > 0.0 {
                         threshold_result = "yes";
                     }
-                    if debug {
-                        (format!("{}_threshold-crossed-debug", t), threshold_result.to_string())
-                    } else {
-                        (format!("{}_threshold-crossed", t), threshold_result.to_string())
-                    }
+                    (topic, threshold_result.to_string())
                 },
-                _ => {
-                    (format!("{}_threshold-crossed", t), "".to_string())
+                _ => (topic, "".to_string()),
+            }
+        }
+        ProcessType::Watchdog { per_entity, .. } => {
+            let topic = match (&sensor_topic, per_entity) {
+                (Some(sensor_topic), true) => format!("i483-sensors-{}_status{}", sensor_topic.entity, debug_suffix),
+                (Some(sensor_topic), false) => sensor_topic.derived("", &format!("_status{}", debug_suffix)),
+                (None, _) => format!("{}_status{}", t, debug_suffix),
+            };
+            match data {
+                ProcessData::Watchdog(value) => (topic, if value > 0.0 { "online" } else { "offline" }.to_string()),
+                _ => (topic, "".to_string()),
+            }
+        }
+        ProcessType::Percentile { .. } => {
+            let topic = match &sensor_topic {
+                Some(sensor_topic) => sensor_topic.derived("_percentile", debug_suffix),
+                None => format!("{}_percentile{}", t, debug_suffix),
+            };
+            match data {
+                ProcessData::Percentile(_, Some(statistics)) => {
+                    let quantiles: Vec<String> = statistics.quantiles.iter().map(|(name, value)| format!("{}={}", name, value)).collect();
+                    (topic, quantiles.join(","))
                 },
+                _ => (topic, "".to_string()),
+            }
+        }
+        ProcessType::Forecast { .. } => {
+            let (sensor_suffix, data_type_suffix) = match data {
+                ProcessData::ForecastAlert(_) => ("", format!("_forecast-crossed{}", debug_suffix)),
+                _ => ("_forecast", debug_suffix.to_string()),
+            };
+            let topic = match &sensor_topic {
+                Some(sensor_topic) => sensor_topic.derived(sensor_suffix, &data_type_suffix),
+                None => format!("{}{}{}", t, sensor_suffix, data_type_suffix),
+            };
+            match data {
+                ProcessData::Forecast(value) => (topic, value.to_string()),
+                ProcessData::ForecastAlert(value) => (topic, if value > 0.0 { "yes" } else { "no" }.to_string()),
+                _ => (topic, "".to_string()),
+            }
+        }
+        ProcessType::Hampel { .. } => {
+            let (sensor_suffix, data_type_suffix) = match data {
+                ProcessData::HampelReplaced(_) => ("", format!("_replaced{}", debug_suffix)),
+                _ => ("_clean", debug_suffix.to_string()),
+            };
+            let topic = match &sensor_topic {
+                Some(sensor_topic) => sensor_topic.derived(sensor_suffix, &data_type_suffix),
+                None => format!("{}{}{}", t, sensor_suffix, data_type_suffix),
+            };
+            match data {
+                ProcessData::Hampel(value) | ProcessData::HampelReplaced(value) => (topic, value.to_string()),
+                _ => (topic, "".to_string()),
+            }
+        }
+        ProcessType::Consistency { .. } => {
+            let suffix = match data {
+                ProcessData::ConsistencyStatus(_) => "_consistency",
+                _ => "_drift",
+            };
+            let topic = match &sensor_topic {
+                Some(sensor_topic) => format!("i483-sensors-{}-{}{}{}", sensor_topic.entity, sensor_topic.data_type, suffix, debug_suffix),
+                None => format!("{}{}{}", t, suffix, debug_suffix),
+            };
+            match data {
+                ProcessData::Consistency(value) => (topic, value.to_string()),
+                ProcessData::ConsistencyStatus(status) => (topic, status.to_string()),
+                _ => (topic, "".to_string()),
+            }
+        }
+        ProcessType::Downsample { period, reducer } => {
+            let sensor_suffix = format!("_{}s-{}", period, reducer);
+            let topic = match &sensor_topic {
+                Some(sensor_topic) => sensor_topic.derived(&sensor_suffix, debug_suffix),
+                None => format!("{}{}{}", t, sensor_suffix, debug_suffix),
+            };
+            match data {
+                ProcessData::Downsample(value) => (topic, value.to_string()),
+                _ => (topic, "".to_string()),
+            }
+        }
+        ProcessType::Expression { name, .. } => {
+            let topic = match &sensor_topic {
+                Some(sensor_topic) => sensor_topic.derived("", &format!("_{}{}", name, debug_suffix)),
+                None => format!("{}_{}{}", t, name, debug_suffix),
+            };
+            match data {
+                ProcessData::Expression(value, _) => (topic, value.to_string()),
+                _ => (topic, "".to_string()),
             }
         }
     };
@@ -275,9 +631,41 @@ fn generate_payload(process: ProcessType, data: ProcessData, t: &str, debug: boo
 }
 
 
-async fn produce(future_producer: FutureProducer, topic: &str, payload: &String) -> Result<(), rdkafka::error::KafkaError> {
-    println!("Producing message to topic: {}, payload: {}", &topic, &payload);
-    let record: FutureRecord<String, String> = FutureRecord::to(topic).payload(payload);
+// The records are keyed by the entity and the sensor (e.g. `s2420010-SCD41`) to keep the order per sensor.
+fn record_key(topic: &str) -> String {
+    match SensorTopic::parse(topic) {
+        Some(sensor_topic) => sensor_topic.key(),
+        None => topic.to_string(),
+    }
+}
+
+fn record_headers(window: &WindowInfo, pipeline_id: &str) -> OwnedHeaders {
+    let correlation_id = window.correlation_id.clone().unwrap_or(Uuid::new_v4().to_string());
+    let mut headers = OwnedHeaders::new()
+        .insert(Header { key: "correlation-id", value: Some(&correlation_id) })
+        .insert(Header { key: "pipeline-id", value: Some(pipeline_id) });
+    if let Some(source) = window.sources.last() {
+        headers = headers
+            .insert(Header { key: "source-topic", value: Some(&source.topic) })
+            .insert(Header { key: "source-partition", value: Some(&source.partition.to_string()) })
+            .insert(Header { key: "source-offset", value: Some(&source.last_offset.to_string()) });
+    }
+    headers
+}
+
+fn rejected_headers(source: &SourceRecord, violation: &Violation) -> OwnedHeaders {
+    let correlation_id = source.correlation_id.clone().unwrap_or(Uuid::new_v4().to_string());
+    OwnedHeaders::new()
+        .insert(Header { key: "validation-error", value: Some(&violation.to_string()) })
+        .insert(Header { key: "correlation-id", value: Some(&correlation_id) })
+        .insert(Header { key: "source-topic", value: Some(&source.topic) })
+        .insert(Header { key: "source-partition", value: Some(&source.partition.to_string()) })
+        .insert(Header { key: "source-offset", value: Some(&source.offset.to_string()) })
+}
+
+async fn produce(future_producer: FutureProducer, topic: &str, key: &str, headers: OwnedHeaders, payload: &[u8]) -> Result<(), rdkafka::error::KafkaError> {
+    println!("Producing message to topic: {}, key: {}, payload: {}", &topic, key, String::from_utf8_lossy(payload));
+    let record: FutureRecord<str, [u8]> = FutureRecord::to(topic).key(key).headers(headers).payload(payload);
     let produce_future = future_producer.send(
         record,
         Timeout::Never
@@ -293,3 +681,107 @@ async fn produce(future_producer: FutureProducer, topic: &str, payload: &String)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consistency::Status;
+    use crate::downsample::Reducer;
+
+    #[tokio::test]
+    async fn test_parse_invalid_payload_as_nan() {
+        let registry = SchemaRegistry::new(&None);
+        assert_eq!(parse_kafka_payload(Some(b"812.5\n"), &InputFormat::Raw, &registry).await, 812.5);
+        assert!(parse_kafka_payload(Some(b"abc"), &InputFormat::Raw, &registry).await.is_nan());
+        assert!(parse_kafka_payload(None, &InputFormat::Raw, &registry).await.is_nan());
+        assert!(parse_kafka_payload(Some(b"23.4"), &InputFormat::Avro, &registry).await.is_nan());
+    }
+
+    #[test]
+    fn test_max_age_covers_reorder_tolerance() {
+        let args = |max_age, reorder_tolerance| match crate::cli::parse_args(["kafka-publisher", "process", "--max-age", max_age, "--reorder", reorder_tolerance].iter().map(|arg| arg.to_string()).collect()) {
+            crate::cli::Command::Process(args) => args,
+            _ => panic!("unexpected command"),
+        };
+        assert_eq!(max_age(&args("3000", "0")), Some(Duration::seconds(3)));
+        assert_eq!(max_age(&args("3000", "5000")), Some(Duration::seconds(6)));
+        assert_eq!(max_age(&args("10000", "500")), Some(Duration::seconds(10)));
+        assert_eq!(max_age(&args("0", "500")), None);
+    }
+
+    #[test]
+    fn test_record_key() {
+        assert_eq!(record_key("i483-sensors-s2420010-SCD41-co2"), "s2420010-SCD41");
+        assert_eq!(record_key("i483/sensors/s2420010/BMP180/temperature"), "s2420010-BMP180");
+        assert_eq!(record_key("other-topic"), "other-topic");
+    }
+
+    #[test]
+    fn test_pairing_topics_and_patterns() {
+        let topics = vec!["i483-sensors-s2420010-SCD41-temperature".to_string(), "^i483-sensors-.*-SCD41-co2$".to_string()];
+        let routes = pairing_topics_and_processes(&topics, &[ProcessType::RollingAverage(30), ProcessType::Threshold(1000)]);
+        assert!(routes[0].matches("i483-sensors-s2420010-SCD41-temperature"));
+        assert!(!routes[0].matches("i483-sensors-s2420011-SCD41-temperature"));
+        assert!(routes[1].matches("i483-sensors-s2420010-SCD41-co2"));
+        assert!(routes[1].matches("i483-sensors-s2420011-SCD41-co2"));
+        assert!(!routes[1].matches("i483-sensors-s2420011-SCD41-co2_threshold-crossed"));
+    }
+
+    #[test]
+    fn test_rejected_headers() {
+        let source = SourceRecord {
+            topic: "i483-sensors-s2420010-SCD41-co2".to_string(),
+            partition: 1,
+            offset: 42,
+            timestamp: Utc::now(),
+            correlation_id: Some("correlation".to_string()),
+            unit: None,
+        };
+        let headers = rejected_headers(&source, &Violation::NotANumber);
+        let headers: Vec<(String, String)> = headers.iter()
+            .map(|header| (header.key.to_string(), String::from_utf8_lossy(header.value.unwrap()).to_string()))
+            .collect();
+        assert!(headers.contains(&("validation-error".to_string(), "not a number".to_string())));
+        assert!(headers.contains(&("correlation-id".to_string(), "correlation".to_string())));
+        assert!(headers.contains(&("source-offset".to_string(), "42".to_string())));
+    }
+
+    #[test]
+    fn test_generate_payload() {
+        let (topic, payload) = generate_payload(ProcessType::RollingAverage(30), ProcessData::RollingAverage(812.5), "i483-sensors-s2420010-SCD41-co2", false);
+        assert_eq!(topic, "i483-sensors-s2420010-SCD41_avg-co2");
+        assert_eq!(payload, "812.5");
+        let (topic, payload) = generate_payload(ProcessType::Threshold(1000), ProcessData::Threshold(1200.0), "i483-sensors-s2420010-SCD41-co2", true);
+        assert_eq!(topic, "i483-sensors-s2420010-SCD41-co2_threshold-crossed-debug");
+        assert_eq!(payload, "yes");
+        let (_, payload) = generate_payload(ProcessType::Threshold(1000), ProcessData::RollingAverage(1200.0), "i483-sensors-s2420010-SCD41-co2", false);
+        assert!(payload.is_empty());
+        let forecast = ProcessType::Forecast { horizon: 600, threshold: 1000, window: 600 };
+        let (topic, payload) = generate_payload(forecast.clone(), ProcessData::Forecast(1010.5), "i483-sensors-s2420010-SCD41-co2", false);
+        assert_eq!(topic, "i483-sensors-s2420010-SCD41_forecast-co2");
+        assert_eq!(payload, "1010.5");
+        let (topic, payload) = generate_payload(forecast, ProcessData::ForecastAlert(1010.5), "i483-sensors-s2420010-SCD41-co2", false);
+        assert_eq!(topic, "i483-sensors-s2420010-SCD41-co2_forecast-crossed");
+        assert_eq!(payload, "yes");
+        let hampel = ProcessType::Hampel { window: 7, n_sigmas: 3 };
+        let (topic, payload) = generate_payload(hampel, ProcessData::HampelReplaced(5000.0), "i483-sensors-s2420010-SCD41-co2", false);
+        assert_eq!(topic, "i483-sensors-s2420010-SCD41-co2_replaced");
+        assert_eq!(payload, "5000");
+        let consistency = ProcessType::Consistency { tolerance_milli: 1500, timeout: 60 };
+        let (topic, payload) = generate_payload(consistency, ProcessData::ConsistencyStatus(Status::Stale("SCD41".to_string())), "i483-sensors-s2420010-BMP180-temperature", false);
+        assert_eq!(topic, "i483-sensors-s2420010-temperature_consistency");
+        assert_eq!(payload, "stale:SCD41");
+        let downsample = ProcessType::Downsample { period: 300, reducer: Reducer::Lttb };
+        let (topic, payload) = generate_payload(downsample, ProcessData::Downsample(1200.0), "i483-sensors-s2420010-SCD41-co2", false);
+        assert_eq!(topic, "i483-sensors-s2420010-SCD41_300s-lttb-co2");
+        assert_eq!(payload, "1200");
+        let expression = ProcessType::Expression { name: "hpa".to_string(), program: Program::compile("value * 0.01").unwrap() };
+        let (topic, payload) = generate_payload(expression, ProcessData::Expression(1013.25, HashMap::new()), "i483-sensors-s2420010-BMP180-air_pressure", false);
+        assert_eq!(topic, "i483-sensors-s2420010-BMP180-air_pressure_hpa");
+        assert_eq!(payload, "1013.25");
+        let watchdog = ProcessType::Watchdog { period: 15, multiple: 3, per_entity: true };
+        let (topic, payload) = generate_payload(watchdog, ProcessData::Watchdog(0.0), "i483-sensors-s2420010-SCD41-co2", false);
+        assert_eq!(topic, "i483-sensors-s2420010_status");
+        assert_eq!(payload, "offline");
+    }
+}
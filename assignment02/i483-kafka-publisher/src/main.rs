@@ -1,5 +1,20 @@
+mod alert;
+mod bridge;
 mod cli;
+mod consistency;
+mod downsample;
+mod expression;
+mod filter;
+mod forecast;
 mod kafka;
+mod mqtt;
+mod ordering;
+mod output;
+mod schema;
+mod sketch;
+mod transform;
+mod units;
+mod validation;
 mod worker;
 
 #[tokio::main]
@@ -12,10 +27,13 @@ async fn main() {
             cli::print_usage();
         }
         cli::Command::Process(args) => {
-            kafka::process(&args.host, &args.topics, &args.processes, &args.debug, &args.dry_run).await;
+            kafka::process(&args).await;
         }
         cli::Command::Listen(args) => {
-            let _ = kafka::listen(&args.host, &args.topics, &args.debug).await;
+            let _ = kafka::listen(&args).await;
+        }
+        cli::Command::Bridge(args) => {
+            bridge::bridge(&args).await;
         }
     }
 }
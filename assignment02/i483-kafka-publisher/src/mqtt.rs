@@ -0,0 +1,72 @@
+/*
+    This is the mqtt module. It connects to the MQTT broker of the ESP32 nodes with rumqttc (MQTT v5).
+    * The session is persistent for the expiry, so the broker keeps the subscriptions and the unacknowledged messages
+      while the process restarts.
+    * The event loop reconnects on the next poll after an error. The delay is doubled up to the maximum, same as connect_wifi of mqtt_pub.py.
+*/
+use std::time::Duration;
+use rumqttc::v5::{AsyncClient, ConnectionError, EventLoop, MqttOptions};
+use rumqttc::v5::mqttbytes::v5::ConnectProperties;
+
+const INITIAL_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(120);
+
+
+/// Parses `<host>[:<port>]` of the broker (default port: 1883).
+pub fn parse_broker(broker: &str) -> Option<(String, u16)> {
+    let (host, port) = match broker.split_once(':') {
+        Some((host, port)) => (host, port.parse().ok()?),
+        None => (broker, 1883),
+    };
+    if host.is_empty() || host.contains("--") {
+        return None;
+    }
+    Some((host.to_string(), port))
+}
+
+/// With the manual acknowledgement, a QoS 1/2 message is acknowledged only by `AsyncClient::ack`.
+pub fn connect(broker: &(String, u16), client_id: &str, session_expiry: u32, manual_acks: bool) -> (AsyncClient, EventLoop) {
+    let mut mqtt_options = MqttOptions::new(client_id, &broker.0, broker.1);
+    mqtt_options.set_keep_alive(Duration::from_secs(30));
+    mqtt_options.set_manual_acks(manual_acks);
+    if session_expiry > 0 {
+        let mut properties = ConnectProperties::new();
+        properties.session_expiry_interval = Some(session_expiry);
+        mqtt_options.set_clean_start(false);
+        mqtt_options.set_connect_properties(properties);
+    }
+    AsyncClient::new(mqtt_options, 100)
+}
+
+pub struct Reconnect {
+    delay: Duration,
+}
+
+impl Reconnect {
+    pub fn new() -> Reconnect {
+        Reconnect { delay: INITIAL_DELAY }
+    }
+
+    pub async fn wait(&mut self, error: &ConnectionError) {
+        println!("MQTT connection error: {}, reconnecting in {} s", error, self.delay.as_secs());
+        tokio::time::sleep(self.delay).await;
+        self.delay = (self.delay * 2).min(MAX_DELAY);
+    }
+
+    pub fn reset(&mut self) {
+        self.delay = INITIAL_DELAY;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_broker() {
+        assert_eq!(parse_broker("150.65.230.59"), Some(("150.65.230.59".to_string(), 1883)));
+        assert_eq!(parse_broker("localhost:1884"), Some(("localhost".to_string(), 1884)));
+        assert_eq!(parse_broker("localhost:port"), None);
+        assert_eq!(parse_broker(""), None);
+    }
+}
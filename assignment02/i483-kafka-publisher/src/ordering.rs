@@ -0,0 +1,212 @@
+/*
+    This is the ordering module. It is the optional stage before the processes.
+    * Deduplicator: drops the duplicate records within the horizon, identified by the topic, the key and
+      the timestamp of the record, or by the hash of the topic and the payload.
+    * Reorderer: holds the records for the tolerance and releases them in the order of the event time.
+      The records which arrive after a later record is released are too late, and dropped.
+*/
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Display, Formatter};
+use std::hash::{Hash, Hasher};
+use chrono::{DateTime, Duration, Utc};
+
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OrderingStats {
+    pub duplicates: u64,
+    pub reordered: u64,
+    pub late: u64,
+}
+
+impl Display for OrderingStats {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "dropped {} duplicates, reordered {}, dropped {} late", self.duplicates, self.reordered, self.late)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DedupKey {
+    KeyAndTimestamp,
+    ContentHash,
+}
+
+pub struct Deduplicator {
+    horizon: Duration,
+    by: DedupKey,
+    seen: HashMap<u64, DateTime<Utc>>,
+}
+
+impl Deduplicator {
+    pub fn new(horizon: u64, by: DedupKey) -> Deduplicator {
+        Deduplicator {
+            horizon: Duration::seconds(horizon as i64),
+            by,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Returns true if the record is seen within the horizon.
+    pub fn is_duplicate(&mut self, topic: &str, key: Option<&[u8]>, timestamp: DateTime<Utc>, payload: Option<&[u8]>, now: DateTime<Utc>) -> bool {
+        let mut hasher = DefaultHasher::new();
+        topic.hash(&mut hasher);
+        match self.by {
+            DedupKey::KeyAndTimestamp => {
+                key.hash(&mut hasher);
+                timestamp.timestamp_millis().hash(&mut hasher);
+            },
+            DedupKey::ContentHash => payload.hash(&mut hasher),
+        }
+        let id = hasher.finish();
+        let horizon = self.horizon;
+        self.seen.retain(|_, seen_at| now - *seen_at <= horizon);
+        self.seen.insert(id, now).is_some()
+    }
+}
+
+pub struct Reorderer<T> {
+    tolerance: Duration,
+    buffer: BTreeMap<(DateTime<Utc>, u64), T>, // (event time, arrival sequence) -> record
+    sequence: u64,
+    latest: Option<DateTime<Utc>>, // The latest event time which has arrived.
+    released: Option<DateTime<Utc>>, // The latest event time which has been released.
+}
+
+impl<T> Reorderer<T> {
+    pub fn new(tolerance: u64) -> Reorderer<T> {
+        Reorderer {
+            tolerance: Duration::milliseconds(tolerance as i64),
+            buffer: BTreeMap::new(),
+            sequence: 0,
+            latest: None,
+            released: None,
+        }
+    }
+
+    /// Buffers the record, and counts it if it is reordered or too late.
+    pub fn push(&mut self, event_time: DateTime<Utc>, record: T, stats: &mut OrderingStats) {
+        if self.released.is_some_and(|released| event_time < released) {
+            stats.late += 1;
+            return;
+        }
+        if self.latest.is_some_and(|latest| event_time < latest) {
+            stats.reordered += 1;
+        }
+        self.latest = Some(self.latest.map_or(event_time, |latest| latest.max(event_time)));
+        self.sequence += 1;
+        self.buffer.insert((event_time, self.sequence), record);
+    }
+
+    /// Releases the records which are older than the tolerance, in the order of the event time.
+    pub fn release(&mut self, now: DateTime<Utc>) -> Vec<T> {
+        let mut records = Vec::new();
+        while let Some(entry) = self.buffer.first_entry() {
+            let (event_time, _) = *entry.key();
+            if event_time + self.tolerance > now {
+                break;
+            }
+            self.released = Some(event_time);
+            records.push(entry.remove());
+        }
+        records
+    }
+}
+
+/// The deduplication and the reordering, either of which may be disabled.
+pub struct Ordering<T> {
+    deduplicator: Option<Deduplicator>,
+    reorderer: Option<Reorderer<T>>,
+    pub stats: OrderingStats,
+}
+
+impl<T> Ordering<T> {
+    pub fn new(dedup_horizon: u64, dedup_by: DedupKey, reorder_tolerance: u64) -> Ordering<T> {
+        Ordering {
+            deduplicator: if dedup_horizon > 0 { Some(Deduplicator::new(dedup_horizon, dedup_by)) } else { None },
+            reorderer: if reorder_tolerance > 0 { Some(Reorderer::new(reorder_tolerance)) } else { None },
+            stats: OrderingStats::default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.deduplicator.is_some() || self.reorderer.is_some()
+    }
+
+    pub fn is_reordering(&self) -> bool {
+        self.reorderer.is_some()
+    }
+
+    /// Returns true (and counts it) if the record is a duplicate. Nothing is a duplicate without the deduplication.
+    pub fn is_duplicate(&mut self, topic: &str, key: Option<&[u8]>, timestamp: DateTime<Utc>, payload: Option<&[u8]>, now: DateTime<Utc>) -> bool {
+        let duplicate = self.deduplicator.as_mut()
+            .is_some_and(|deduplicator| deduplicator.is_duplicate(topic, key, timestamp, payload, now));
+        if duplicate {
+            self.stats.duplicates += 1;
+        }
+        duplicate
+    }
+
+    /// Returns the records which are ready. Without the reordering, the record itself is ready.
+    pub fn push(&mut self, event_time: DateTime<Utc>, record: T, now: DateTime<Utc>) -> Vec<T> {
+        match self.reorderer.as_mut() {
+            Some(reorderer) => {
+                reorderer.push(event_time, record, &mut self.stats);
+                reorderer.release(now)
+            },
+            None => vec![record],
+        }
+    }
+
+    pub fn release(&mut self, now: DateTime<Utc>) -> Vec<T> {
+        match self.reorderer.as_mut() {
+            Some(reorderer) => reorderer.release(now),
+            None => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deduplicate() {
+        let now = Utc::now();
+        let mut by_key = Deduplicator::new(60, DedupKey::KeyAndTimestamp);
+        assert!(!by_key.is_duplicate("co2", Some(b"s2420010-SCD41"), now, Some(b"812"), now));
+        assert!(by_key.is_duplicate("co2", Some(b"s2420010-SCD41"), now, Some(b"813"), now));
+        assert!(!by_key.is_duplicate("co2", Some(b"s2420010-SCD41"), now + Duration::seconds(15), Some(b"812"), now));
+        assert!(!by_key.is_duplicate("co2", Some(b"s2420010-SCD41"), now, Some(b"812"), now + Duration::seconds(61)));
+
+        let mut by_hash = Deduplicator::new(60, DedupKey::ContentHash);
+        assert!(!by_hash.is_duplicate("co2", None, now, Some(b"812"), now));
+        assert!(by_hash.is_duplicate("co2", None, now + Duration::seconds(1), Some(b"812"), now));
+        assert!(!by_hash.is_duplicate("temperature", None, now, Some(b"812"), now));
+    }
+
+    #[test]
+    fn test_reorder() {
+        let start = Utc::now();
+        let mut stats = OrderingStats::default();
+        let mut reorderer = Reorderer::new(500);
+        reorderer.push(start + Duration::milliseconds(200), "second", &mut stats);
+        reorderer.push(start, "first", &mut stats);
+        reorderer.push(start + Duration::milliseconds(400), "third", &mut stats);
+        assert!(reorderer.release(start + Duration::milliseconds(100)).is_empty());
+        assert_eq!(reorderer.release(start + Duration::milliseconds(750)), vec!["first", "second"]);
+        reorderer.push(start + Duration::milliseconds(100), "late", &mut stats);
+        assert_eq!(reorderer.release(start + Duration::seconds(1)), vec!["third"]);
+        assert_eq!(stats, OrderingStats { duplicates: 0, reordered: 1, late: 1 });
+    }
+
+    #[test]
+    fn test_disabled_ordering_passes_through() {
+        let now = Utc::now();
+        let mut ordering = Ordering::new(0, DedupKey::KeyAndTimestamp, 0);
+        assert!(!ordering.is_enabled());
+        assert!(!ordering.is_duplicate("co2", None, now, Some(b"812"), now));
+        assert!(!ordering.is_duplicate("co2", None, now, Some(b"812"), now));
+        assert_eq!(ordering.push(now, 812, now), vec![812]);
+        assert_eq!(ordering.stats, OrderingStats::default());
+    }
+}
@@ -0,0 +1,182 @@
+/*
+    This is the output module. It converts the result of a process into the payload of the record.
+
+    * Raw: the bare string (e.g. `23.4` or `yes`) which is expected by the course topics.
+    * Json / MessagePack: an envelope which contains the value and the metadata of the window
+      (processor, window start/end, sample count, source offsets and emit time), and the statistics
+      (quantiles and histogram) of the percentile process.
+    * Avro / Protobuf: the same envelope in the Confluent wire format (see the schema module).
+*/
+use std::collections::BTreeMap;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use i483_sensors::SensorTopic;
+use crate::cli::{OutputFormat, Param, ProcessType};
+use crate::schema::{self, SchemaRegistry, SchemaType};
+use crate::sketch::Statistics;
+use crate::worker::{SourceRange, WindowInfo};
+
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum OutputValue {
+    Number(f64),
+    Text(String),
+}
+
+impl From<&str> for OutputValue {
+    fn from(value: &str) -> Self {
+        match value.parse::<f64>() {
+            Ok(number) => OutputValue::Number(number),
+            Err(_) => OutputValue::Text(value.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Processor {
+    pub kind: &'static str,
+    pub params: BTreeMap<&'static str, Param>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Window {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Envelope {
+    pub value: OutputValue,
+    pub unit: Option<String>,
+    pub entity: Option<String>,
+    pub processor: Processor,
+    pub window: Window,
+    pub sample_count: u64,
+    pub sources: Vec<SourceRange>,
+    pub emitted_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub statistics: Option<Statistics>,
+}
+
+impl Envelope {
+    pub fn new(process: &ProcessType, source_topic: &str, value: &str, statistics: Option<&Statistics>, window: &WindowInfo) -> Envelope {
+        let sensor_topic = SensorTopic::parse(source_topic);
+        let unit = match process {
+            ProcessType::Threshold(_) | ProcessType::Watchdog { .. } | ProcessType::Expression { .. } => None,
+            ProcessType::Forecast { .. } | ProcessType::Consistency { .. } if value.parse::<f64>().is_err() => None, // The alert and the status.
+            _ => window.unit.clone().or(sensor_topic.as_ref().and_then(|t| t.unit()).map(|unit| unit.to_string())),
+        };
+        Envelope {
+            value: OutputValue::from(value),
+            unit,
+            entity: sensor_topic.map(|t| t.entity),
+            processor: Processor {
+                kind: process.kind(),
+                params: process.params().into_iter().collect(),
+            },
+            window: Window {
+                start: window.start,
+                end: window.end,
+            },
+            sample_count: window.count,
+            sources: window.sources.clone(),
+            emitted_at: Utc::now(),
+            statistics: statistics.cloned(),
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn encode_payload(format: &OutputFormat, registry: &SchemaRegistry, topic: &str, process: &ProcessType, source_topic: &str, value: &str, statistics: Option<&Statistics>, window: &WindowInfo) -> anyhow::Result<Vec<u8>> {
+    let envelope = Envelope::new(process, source_topic, value, statistics, window);
+    match format {
+        OutputFormat::Raw => Ok(value.as_bytes().to_vec()),
+        OutputFormat::Json => Ok(serde_json::to_vec(&envelope)?),
+        OutputFormat::MessagePack => Ok(rmp_serde::to_vec_named(&envelope)?),
+        OutputFormat::Avro => schema::encode(SchemaType::Avro, registry, topic, &envelope).await,
+        OutputFormat::Protobuf => schema::encode(SchemaType::Protobuf, registry, topic, &envelope).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::worker::SourceRecord;
+
+    fn window() -> WindowInfo {
+        let start = DateTime::from_timestamp_millis(1_700_000_000_000).unwrap();
+        let mut window = WindowInfo::new(start);
+        for offset in [10, 11, 12] {
+            window.record(&SourceRecord {
+                topic: "i483-sensors-s2420010-SCD41-temperature".to_string(),
+                partition: 0,
+                offset,
+                timestamp: start + chrono::Duration::seconds(offset),
+                correlation_id: None,
+                unit: None,
+            });
+        }
+        window
+    }
+
+    #[tokio::test]
+    async fn test_encode_raw_payload() {
+        let registry = SchemaRegistry::new(&None);
+        let payload = encode_payload(&OutputFormat::Raw, &registry, "out", &ProcessType::RollingAverage(30), "i483-sensors-s2420010-SCD41-temperature", "23.4", None, &window()).await.unwrap();
+        assert_eq!(payload, b"23.4".to_vec());
+    }
+
+    #[tokio::test]
+    async fn test_encode_json_payload() {
+        let registry = SchemaRegistry::new(&None);
+        let payload = encode_payload(&OutputFormat::Json, &registry, "out", &ProcessType::RollingAverage(30), "i483-sensors-s2420010-SCD41-temperature", "23.4", None, &window()).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(json["value"], 23.4);
+        assert_eq!(json["unit"], "°C");
+        assert_eq!(json["entity"], "s2420010");
+        assert_eq!(json["processor"]["kind"], "rolling-average");
+        assert_eq!(json["processor"]["params"]["duration"], 30);
+        assert_eq!(json["sample_count"], 3);
+        assert_eq!(json["sources"][0]["first_offset"], 10);
+        assert_eq!(json["sources"][0]["last_offset"], 12);
+    }
+
+    #[tokio::test]
+    async fn test_encode_threshold_payload() {
+        let registry = SchemaRegistry::new(&None);
+        let payload = encode_payload(&OutputFormat::Json, &registry, "out", &ProcessType::Threshold(1000), "i483-sensors-s2420010-SCD41-co2", "yes", None, &window()).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(json["value"], "yes");
+        assert!(json["unit"].is_null());
+    }
+
+    #[tokio::test]
+    async fn test_encode_expression_source() {
+        let registry = SchemaRegistry::new(&None);
+        let process = ProcessType::Expression { name: "hpa".to_string(), program: crate::expression::Program::compile("value * 0.01").unwrap() };
+        let payload = encode_payload(&OutputFormat::Json, &registry, "out", &process, "i483-sensors-s2420010-BMP180-air_pressure", "1013.25", None, &window()).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(json["processor"]["params"]["source"], "value * 0.01");
+    }
+
+    #[tokio::test]
+    async fn test_encode_percentile_payload() {
+        let registry = SchemaRegistry::new(&None);
+        let statistics = Statistics {
+            quantiles: BTreeMap::from([("p50".to_string(), 812.0), ("p90".to_string(), 1010.0), ("p99".to_string(), 1200.0)]),
+            min: 420.0,
+            max: 1210.0,
+            histogram: vec![crate::sketch::Bucket { lower: 800.0, upper: 900.0, count: 3 }],
+        };
+        let process = ProcessType::Percentile { duration: 300, bucket_width: 100 };
+        let payload = encode_payload(&OutputFormat::Json, &registry, "out", &process, "i483-sensors-s2420010-SCD41-co2", "p50=812", Some(&statistics), &window()).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(json["unit"], "ppm");
+        assert_eq!(json["statistics"]["quantiles"]["p90"], 1010.0);
+        assert_eq!(json["statistics"]["histogram"][0]["count"], 3);
+        let payload = encode_payload(&OutputFormat::Json, &registry, "out", &ProcessType::RollingAverage(30), "i483-sensors-s2420010-SCD41-co2", "812", None, &window()).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        assert!(json.get("statistics").is_none());
+    }
+}
@@ -0,0 +1,584 @@
+/*
+    This is the schema module. It encodes the processor results to Avro or Protobuf with the
+    Confluent wire format, and decodes the inputs which are encoded in the same way.
+
+    Confluent wire format: [magic byte 0][schema id (4 bytes, big endian)][message indexes (Protobuf only)][body]
+
+    The schemas are registered to the schema registry and cached by the SchemaRegistry.
+    * Remote: the Confluent compatible schema registry (e.g. http://localhost:8081)
+    * Local: the in-process stand-in. The ids are only valid in the running process.
+*/
+use std::collections::HashMap;
+use anyhow::{anyhow, bail};
+use apache_avro::types::Value;
+use apache_avro::{from_avro_datum, to_avro_datum, Schema};
+use prost::Message;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::Mutex;
+use crate::cli::Param;
+use crate::output::{Envelope, OutputValue};
+
+
+pub const AVRO_SCHEMA: &str = r#"{
+    "type": "record",
+    "name": "ProcessResult",
+    "namespace": "i483.sensors",
+    "fields": [
+        {"name": "value", "type": ["double", "string"]},
+        {"name": "unit", "type": ["null", "string"], "default": null},
+        {"name": "entity", "type": ["null", "string"], "default": null},
+        {"name": "processor", "type": {
+            "type": "record",
+            "name": "Processor",
+            "fields": [
+                {"name": "kind", "type": "string"},
+                {"name": "params", "type": {"type": "map", "values": ["long", "string"]}}
+            ]
+        }},
+        {"name": "window", "type": {
+            "type": "record",
+            "name": "Window",
+            "fields": [
+                {"name": "start", "type": {"type": "long", "logicalType": "timestamp-millis"}},
+                {"name": "end", "type": {"type": "long", "logicalType": "timestamp-millis"}}
+            ]
+        }},
+        {"name": "sample_count", "type": "long"},
+        {"name": "sources", "type": {"type": "array", "items": {
+            "type": "record",
+            "name": "Source",
+            "fields": [
+                {"name": "topic", "type": "string"},
+                {"name": "partition", "type": "int"},
+                {"name": "first_offset", "type": "long"},
+                {"name": "last_offset", "type": "long"}
+            ]
+        }}},
+        {"name": "emitted_at", "type": {"type": "long", "logicalType": "timestamp-millis"}},
+        {"name": "statistics", "type": ["null", {
+            "type": "record",
+            "name": "Statistics",
+            "fields": [
+                {"name": "quantiles", "type": {"type": "map", "values": "double"}},
+                {"name": "min", "type": "double"},
+                {"name": "max", "type": "double"},
+                {"name": "histogram", "type": {"type": "array", "items": {
+                    "type": "record",
+                    "name": "Bucket",
+                    "fields": [
+                        {"name": "lower", "type": "double"},
+                        {"name": "upper", "type": "double"},
+                        {"name": "count", "type": "long"}
+                    ]
+                }}}
+            ]
+        }], "default": null}
+    ]
+}"#;
+
+pub const PROTOBUF_SCHEMA: &str = r#"syntax = "proto3";
+package i483.sensors;
+
+message ProcessResult {
+  optional double number = 1;
+  optional string text = 2;
+  optional string unit = 3;
+  string processor_kind = 4;
+  map<string, uint64> processor_params = 5;
+  int64 window_start_ms = 6;
+  int64 window_end_ms = 7;
+  uint64 sample_count = 8;
+  repeated Source sources = 9;
+  int64 emitted_at_ms = 10;
+  optional string entity = 11;
+  optional Statistics statistics = 12;
+  map<string, string> processor_text_params = 13;
+}
+
+message Source {
+  string topic = 1;
+  int32 partition = 2;
+  int64 first_offset = 3;
+  int64 last_offset = 4;
+}
+
+message Statistics {
+  map<string, double> quantiles = 1;
+  double min = 2;
+  double max = 3;
+  repeated Bucket histogram = 4;
+}
+
+message Bucket {
+  double lower = 1;
+  double upper = 2;
+  uint64 count = 3;
+}
+"#;
+
+const MAGIC_BYTE: u8 = 0;
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ProtoProcessResult {
+    #[prost(double, optional, tag = "1")]
+    pub number: Option<f64>,
+    #[prost(string, optional, tag = "2")]
+    pub text: Option<String>,
+    #[prost(string, optional, tag = "3")]
+    pub unit: Option<String>,
+    #[prost(string, tag = "4")]
+    pub processor_kind: String,
+    #[prost(map = "string, uint64", tag = "5")]
+    pub processor_params: HashMap<String, u64>,
+    #[prost(int64, tag = "6")]
+    pub window_start_ms: i64,
+    #[prost(int64, tag = "7")]
+    pub window_end_ms: i64,
+    #[prost(uint64, tag = "8")]
+    pub sample_count: u64,
+    #[prost(message, repeated, tag = "9")]
+    pub sources: Vec<ProtoSource>,
+    #[prost(int64, tag = "10")]
+    pub emitted_at_ms: i64,
+    #[prost(string, optional, tag = "11")]
+    pub entity: Option<String>,
+    #[prost(message, optional, tag = "12")]
+    pub statistics: Option<ProtoStatistics>,
+    #[prost(map = "string, string", tag = "13")]
+    pub processor_text_params: HashMap<String, String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ProtoSource {
+    #[prost(string, tag = "1")]
+    pub topic: String,
+    #[prost(int32, tag = "2")]
+    pub partition: i32,
+    #[prost(int64, tag = "3")]
+    pub first_offset: i64,
+    #[prost(int64, tag = "4")]
+    pub last_offset: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ProtoStatistics {
+    #[prost(map = "string, double", tag = "1")]
+    pub quantiles: HashMap<String, f64>,
+    #[prost(double, tag = "2")]
+    pub min: f64,
+    #[prost(double, tag = "3")]
+    pub max: f64,
+    #[prost(message, repeated, tag = "4")]
+    pub histogram: Vec<ProtoBucket>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ProtoBucket {
+    #[prost(double, tag = "1")]
+    pub lower: f64,
+    #[prost(double, tag = "2")]
+    pub upper: f64,
+    #[prost(uint64, tag = "3")]
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SchemaType {
+    Avro,
+    Protobuf,
+}
+
+impl SchemaType {
+    fn name(&self) -> &'static str {
+        match self {
+            SchemaType::Avro => "AVRO",
+            SchemaType::Protobuf => "PROTOBUF",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum RegistryBackend {
+    Local,
+    Remote(String),
+}
+
+#[derive(Default)]
+struct SchemaCache {
+    ids: HashMap<(String, String), u32>, // (subject, schema) -> id
+    schemas: HashMap<u32, String>,
+}
+
+impl SchemaCache {
+    fn insert(&mut self, key: (String, String), id: u32, subject: &str, schema: &str) {
+        println!("Registered schema {} for subject: {}", id, subject);
+        self.ids.insert(key, id);
+        self.schemas.insert(id, schema.to_string());
+    }
+}
+
+#[derive(Deserialize)]
+struct RegisterResponse {
+    id: u32,
+}
+
+#[derive(Deserialize)]
+struct SchemaResponse {
+    schema: String,
+}
+
+pub struct SchemaRegistry {
+    backend: RegistryBackend,
+    http: reqwest::Client,
+    cache: Mutex<SchemaCache>,
+}
+
+impl SchemaRegistry {
+    pub fn new(url: &Option<String>) -> SchemaRegistry {
+        let backend = match url {
+            Some(url) if url != "local" => RegistryBackend::Remote(url.trim_end_matches('/').to_string()),
+            _ => RegistryBackend::Local,
+        };
+        SchemaRegistry {
+            backend,
+            http: reqwest::Client::new(),
+            cache: Mutex::new(SchemaCache::default()),
+        }
+    }
+
+    // The cache is not locked during the request to the registry, so the other records are not blocked by it.
+    // The same schema may be registered twice at once, which the registry answers with the same id.
+    pub async fn register(&self, subject: &str, schema_type: SchemaType, schema: &str) -> anyhow::Result<u32> {
+        let key = (subject.to_string(), schema.to_string());
+        let url = {
+            let mut cache = self.cache.lock().await;
+            if let Some(id) = cache.ids.get(&key) {
+                return Ok(*id);
+            }
+            match &self.backend {
+                RegistryBackend::Local => {
+                    let id = match cache.schemas.iter().find(|(_, s)| s.as_str() == schema) {
+                        Some((id, _)) => *id,
+                        None => cache.schemas.len() as u32 + 1,
+                    };
+                    cache.insert(key, id, subject, schema);
+                    return Ok(id);
+                },
+                RegistryBackend::Remote(url) => url,
+            }
+        };
+        let response = self.http.post(format!("{}/subjects/{}/versions", url, subject))
+            .header("Content-Type", "application/vnd.schemaregistry.v1+json")
+            .json(&json!({ "schema": schema, "schemaType": schema_type.name() }))
+            .send().await?
+            .error_for_status()?;
+        let id = response.json::<RegisterResponse>().await?.id;
+        self.cache.lock().await.insert(key, id, subject, schema);
+        Ok(id)
+    }
+
+    pub async fn schema(&self, id: u32) -> anyhow::Result<String> {
+        if let Some(schema) = self.cache.lock().await.schemas.get(&id) {
+            return Ok(schema.clone());
+        }
+        match &self.backend {
+            RegistryBackend::Local => Err(anyhow!("unknown schema id: {}", id)),
+            RegistryBackend::Remote(url) => {
+                let response = self.http.get(format!("{}/schemas/ids/{}", url, id))
+                    .send().await?
+                    .error_for_status()?;
+                let schema = response.json::<SchemaResponse>().await?.schema;
+                self.cache.lock().await.schemas.insert(id, schema.clone());
+                Ok(schema)
+            },
+        }
+    }
+}
+
+// The subject follows the TopicNameStrategy of the Confluent serializers.
+fn subject_of(topic: &str) -> String {
+    format!("{}-value", topic)
+}
+
+fn frame(schema_id: u32, message_indexes: &[u8], body: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(5 + message_indexes.len() + body.len());
+    framed.push(MAGIC_BYTE);
+    framed.extend_from_slice(&schema_id.to_be_bytes());
+    framed.extend_from_slice(message_indexes);
+    framed.extend_from_slice(body);
+    framed
+}
+
+fn unframe(payload: &[u8]) -> anyhow::Result<(u32, &[u8])> {
+    if payload.len() < 5 || payload[0] != MAGIC_BYTE {
+        bail!("payload is not in the Confluent wire format");
+    }
+    let schema_id = u32::from_be_bytes([payload[1], payload[2], payload[3], payload[4]]);
+    Ok((schema_id, &payload[5..]))
+}
+
+fn to_avro_value(envelope: &Envelope) -> Value {
+    let value = match &envelope.value {
+        OutputValue::Number(number) => Value::Union(0, Box::new(Value::Double(*number))),
+        OutputValue::Text(text) => Value::Union(1, Box::new(Value::String(text.clone()))),
+    };
+    let unit = match &envelope.unit {
+        Some(unit) => Value::Union(1, Box::new(Value::String(unit.to_string()))),
+        None => Value::Union(0, Box::new(Value::Null)),
+    };
+    let entity = match &envelope.entity {
+        Some(entity) => Value::Union(1, Box::new(Value::String(entity.clone()))),
+        None => Value::Union(0, Box::new(Value::Null)),
+    };
+    let params = envelope.processor.params.iter()
+        .map(|(k, v)| (k.to_string(), match v {
+            Param::Number(number) => Value::Union(0, Box::new(Value::Long(*number as i64))),
+            Param::Text(text) => Value::Union(1, Box::new(Value::String(text.clone()))),
+        }))
+        .collect();
+    let statistics = match &envelope.statistics {
+        Some(statistics) => Value::Union(1, Box::new(Value::Record(vec![
+            ("quantiles".to_string(), Value::Map(statistics.quantiles.iter().map(|(k, v)| (k.clone(), Value::Double(*v))).collect())),
+            ("min".to_string(), Value::Double(statistics.min)),
+            ("max".to_string(), Value::Double(statistics.max)),
+            ("histogram".to_string(), Value::Array(statistics.histogram.iter()
+                .map(|bucket| Value::Record(vec![
+                    ("lower".to_string(), Value::Double(bucket.lower)),
+                    ("upper".to_string(), Value::Double(bucket.upper)),
+                    ("count".to_string(), Value::Long(bucket.count as i64)),
+                ]))
+                .collect())),
+        ]))),
+        None => Value::Union(0, Box::new(Value::Null)),
+    };
+    let sources = envelope.sources.iter()
+        .map(|source| Value::Record(vec![
+            ("topic".to_string(), Value::String(source.topic.clone())),
+            ("partition".to_string(), Value::Int(source.partition)),
+            ("first_offset".to_string(), Value::Long(source.first_offset)),
+            ("last_offset".to_string(), Value::Long(source.last_offset)),
+        ]))
+        .collect();
+    Value::Record(vec![
+        ("value".to_string(), value),
+        ("unit".to_string(), unit),
+        ("entity".to_string(), entity),
+        ("processor".to_string(), Value::Record(vec![
+            ("kind".to_string(), Value::String(envelope.processor.kind.to_string())),
+            ("params".to_string(), Value::Map(params)),
+        ])),
+        ("window".to_string(), Value::Record(vec![
+            ("start".to_string(), Value::TimestampMillis(envelope.window.start.timestamp_millis())),
+            ("end".to_string(), Value::TimestampMillis(envelope.window.end.timestamp_millis())),
+        ])),
+        ("sample_count".to_string(), Value::Long(envelope.sample_count as i64)),
+        ("sources".to_string(), Value::Array(sources)),
+        ("emitted_at".to_string(), Value::TimestampMillis(envelope.emitted_at.timestamp_millis())),
+        ("statistics".to_string(), statistics),
+    ])
+}
+
+fn to_proto(envelope: &Envelope) -> ProtoProcessResult {
+    let (number, text) = match &envelope.value {
+        OutputValue::Number(number) => (Some(*number), None),
+        OutputValue::Text(text) => (None, Some(text.clone())),
+    };
+    ProtoProcessResult {
+        number,
+        text,
+        unit: envelope.unit.clone(),
+        processor_kind: envelope.processor.kind.to_string(),
+        processor_params: envelope.processor.params.iter()
+            .filter_map(|(k, v)| match v {
+                Param::Number(number) => Some((k.to_string(), *number)),
+                Param::Text(_) => None,
+            })
+            .collect(),
+        processor_text_params: envelope.processor.params.iter()
+            .filter_map(|(k, v)| match v {
+                Param::Text(text) => Some((k.to_string(), text.clone())),
+                Param::Number(_) => None,
+            })
+            .collect(),
+        window_start_ms: envelope.window.start.timestamp_millis(),
+        window_end_ms: envelope.window.end.timestamp_millis(),
+        sample_count: envelope.sample_count,
+        sources: envelope.sources.iter()
+            .map(|source| ProtoSource {
+                topic: source.topic.clone(),
+                partition: source.partition,
+                first_offset: source.first_offset,
+                last_offset: source.last_offset,
+            })
+            .collect(),
+        emitted_at_ms: envelope.emitted_at.timestamp_millis(),
+        entity: envelope.entity.clone(),
+        statistics: envelope.statistics.as_ref().map(|statistics| ProtoStatistics {
+            quantiles: statistics.quantiles.iter().map(|(k, v)| (k.clone(), *v)).collect(),
+            min: statistics.min,
+            max: statistics.max,
+            histogram: statistics.histogram.iter()
+                .map(|bucket| ProtoBucket { lower: bucket.lower, upper: bucket.upper, count: bucket.count })
+                .collect(),
+        }),
+    }
+}
+
+pub async fn encode(schema_type: SchemaType, registry: &SchemaRegistry, topic: &str, envelope: &Envelope) -> anyhow::Result<Vec<u8>> {
+    match schema_type {
+        SchemaType::Avro => {
+            let schema_id = registry.register(&subject_of(topic), schema_type, AVRO_SCHEMA).await?;
+            let schema = Schema::parse_str(AVRO_SCHEMA)?;
+            let body = to_avro_datum(&schema, to_avro_value(envelope))?;
+            Ok(frame(schema_id, &[], &body))
+        },
+        SchemaType::Protobuf => {
+            let schema_id = registry.register(&subject_of(topic), schema_type, PROTOBUF_SCHEMA).await?;
+            // The message indexes [0] (the first message in the schema) are encoded as a single 0.
+            Ok(frame(schema_id, &[0], &to_proto(envelope).encode_to_vec()))
+        },
+    }
+}
+
+fn avro_number(value: &Value) -> Option<f32> {
+    match value {
+        Value::Union(_, inner) => avro_number(inner),
+        Value::Double(v) => Some(*v as f32),
+        Value::Float(v) => Some(*v),
+        Value::Int(v) => Some(*v as f32),
+        Value::Long(v) => Some(*v as f32),
+        Value::String(v) => v.parse().ok(),
+        Value::Record(fields) => fields.iter().find(|(name, _)| name == "value").and_then(|(_, v)| avro_number(v)),
+        _ => None,
+    }
+}
+
+// The message indexes are the count and the indexes (the path of the nested message) in zigzag varints.
+fn skip_message_indexes(body: &[u8]) -> anyhow::Result<&[u8]> {
+    let mut cursor = body;
+    let count = decode_zigzag(&mut cursor)?;
+    if count < 0 {
+        bail!("invalid count of the message indexes: {}", count);
+    }
+    for _ in 0..count {
+        decode_zigzag(&mut cursor)?;
+    }
+    Ok(cursor)
+}
+
+fn decode_zigzag(cursor: &mut &[u8]) -> anyhow::Result<i64> {
+    let value = prost::encoding::decode_varint(cursor)?;
+    Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+}
+
+/// Decodes the value of a record which is encoded in the Confluent wire format.
+/// Avro inputs can be any record which has a numeric `value` field (or a bare number).
+/// Protobuf inputs must be the `ProcessResult` message of this module.
+pub async fn decode_value(schema_type: SchemaType, registry: &SchemaRegistry, payload: &[u8]) -> anyhow::Result<f32> {
+    let (schema_id, body) = unframe(payload)?;
+    match schema_type {
+        SchemaType::Avro => {
+            let schema = Schema::parse_str(&registry.schema(schema_id).await?)?;
+            let value = from_avro_datum(&schema, &mut &body[..], None)?;
+            avro_number(&value).ok_or(anyhow!("no numeric value found in the record"))
+        },
+        SchemaType::Protobuf => {
+            let message = ProtoProcessResult::decode(skip_message_indexes(body)?)?;
+            match (message.number, message.text) {
+                (Some(number), _) => Ok(number as f32),
+                (None, Some(text)) => text.parse().map_err(|_| anyhow!("the text is not a number: {}", text)),
+                (None, None) => bail!("no value found in the message"),
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use crate::cli::ProcessType;
+    use crate::sketch::{Bucket, Statistics};
+    use crate::worker::{SourceRecord, WindowInfo};
+    use chrono::Utc;
+
+    fn envelope(value: &str) -> Envelope {
+        let mut window = WindowInfo::new(Utc::now());
+        window.record(&SourceRecord {
+            topic: "i483-sensors-s2420010-SCD41-co2".to_string(),
+            partition: 0,
+            offset: 42,
+            timestamp: Utc::now(),
+            correlation_id: None,
+            unit: None,
+        });
+        Envelope::new(&ProcessType::RollingAverage(30), "i483-sensors-s2420010-SCD41-co2", value, None, &window)
+    }
+
+    #[tokio::test]
+    async fn test_local_registry_caches_ids() {
+        let registry = SchemaRegistry::new(&None);
+        let first = registry.register("topic-a-value", SchemaType::Avro, AVRO_SCHEMA).await.unwrap();
+        let second = registry.register("topic-b-value", SchemaType::Avro, AVRO_SCHEMA).await.unwrap();
+        let third = registry.register("topic-a-value", SchemaType::Protobuf, PROTOBUF_SCHEMA).await.unwrap();
+        assert_eq!(first, second);
+        assert_ne!(first, third);
+        assert_eq!(registry.schema(third).await.unwrap(), PROTOBUF_SCHEMA);
+        assert!(registry.schema(100).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_avro_round_trip() {
+        let registry = SchemaRegistry::new(&Some("local".to_string()));
+        let payload = encode(SchemaType::Avro, &registry, "i483-sensors-s2420010-SCD41_avg-co2", &envelope("812.5")).await.unwrap();
+        assert_eq!(payload[0], MAGIC_BYTE);
+        assert_eq!(decode_value(SchemaType::Avro, &registry, &payload).await.unwrap(), 812.5);
+    }
+
+    #[tokio::test]
+    async fn test_protobuf_round_trip() {
+        let registry = SchemaRegistry::new(&None);
+        let payload = encode(SchemaType::Protobuf, &registry, "i483-sensors-s2420010-SCD41_avg-co2", &envelope("812.5")).await.unwrap();
+        assert_eq!(payload[5], 0);
+        assert_eq!(decode_value(SchemaType::Protobuf, &registry, &payload).await.unwrap(), 812.5);
+        let message = ProtoProcessResult::decode(&payload[6..]).unwrap();
+        assert_eq!(message.sources[0].last_offset, 42);
+        assert_eq!(message.entity, Some("s2420010".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_encode_statistics() {
+        let mut envelope = envelope("p50=812");
+        envelope.statistics = Some(Statistics {
+            quantiles: BTreeMap::from([("p50".to_string(), 812.0), ("p99".to_string(), 1200.0)]),
+            min: 420.0,
+            max: 1210.0,
+            histogram: vec![Bucket { lower: 800.0, upper: 900.0, count: 3 }],
+        });
+        let registry = SchemaRegistry::new(&None);
+        let payload = encode(SchemaType::Avro, &registry, "i483-sensors-s2420010-SCD41_percentile-co2", &envelope).await.unwrap();
+        let schema = Schema::parse_str(AVRO_SCHEMA).unwrap();
+        assert_eq!(from_avro_datum(&schema, &mut &payload[5..], None).unwrap(), to_avro_value(&envelope));
+        let payload = encode(SchemaType::Protobuf, &registry, "i483-sensors-s2420010-SCD41_percentile-co2", &envelope).await.unwrap();
+        let message = ProtoProcessResult::decode(&payload[6..]).unwrap();
+        assert_eq!(message.statistics.unwrap().histogram[0].count, 3);
+    }
+
+    #[test]
+    fn test_skip_nested_message_indexes() {
+        let body = [0x08, 0x01];
+        assert_eq!(skip_message_indexes(&[&[0][..], &body].concat()).unwrap(), body);
+        // [1, 0]: the first message nested in the second one, i.e. the count 2 and the indexes 1 and 0 in zigzag.
+        assert_eq!(skip_message_indexes(&[&[4, 2, 0][..], &body].concat()).unwrap(), body);
+        assert!(skip_message_indexes(&[1]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_decode_rejects_unframed_payload() {
+        let registry = SchemaRegistry::new(&None);
+        assert!(decode_value(SchemaType::Avro, &registry, b"23.4").await.is_err());
+        let payload = encode(SchemaType::Protobuf, &registry, "i483-sensors-s2420010-SCD41-co2_threshold-crossed", &envelope("yes")).await.unwrap();
+        assert!(decode_value(SchemaType::Protobuf, &registry, &payload).await.is_err());
+    }
+}
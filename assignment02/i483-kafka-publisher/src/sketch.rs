@@ -0,0 +1,229 @@
+/*
+    This is the sketch module. It summarizes the distribution of a window.
+    * DDSketch: the quantiles with a relative accuracy, mergeable with the sketches of the other windows.
+      (Masson et al., "DDSketch: A Fast and Fully-Mergeable Quantile Sketch with Relative-Error Guarantees", 2019)
+    * Histogram: the counts of the fixed-width buckets.
+*/
+use std::collections::BTreeMap;
+use serde::Serialize;
+
+
+pub const QUANTILES: [(&str, f64); 3] = [("p50", 0.5), ("p90", 0.9), ("p99", 0.99)];
+const RELATIVE_ACCURACY: f64 = 0.01;
+const MIN_INDEXABLE: f64 = 1e-9; // The values closer to zero are counted as zero.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DDSketch {
+    gamma: f64,
+    positive: BTreeMap<i32, u64>,
+    negative: BTreeMap<i32, u64>,
+    zero: u64,
+    count: u64,
+    min: f64,
+    max: f64,
+}
+
+impl DDSketch {
+    pub fn new() -> DDSketch {
+        DDSketch {
+            gamma: (1.0 + RELATIVE_ACCURACY) / (1.0 - RELATIVE_ACCURACY),
+            positive: BTreeMap::new(),
+            negative: BTreeMap::new(),
+            zero: 0,
+            count: 0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    fn key(&self, value: f64) -> i32 {
+        (value.ln() / self.gamma.ln()).ceil() as i32
+    }
+
+    // The representative value of the bucket, which is within the relative accuracy of its values.
+    fn value(&self, key: i32) -> f64 {
+        2.0 * self.gamma.powi(key) / (self.gamma + 1.0)
+    }
+
+    pub fn add(&mut self, value: f64) {
+        if !value.is_finite() {
+            return;
+        }
+        if value > MIN_INDEXABLE {
+            *self.positive.entry(self.key(value)).or_insert(0) += 1;
+        } else if value < -MIN_INDEXABLE {
+            *self.negative.entry(self.key(-value)).or_insert(0) += 1;
+        } else {
+            self.zero += 1;
+        }
+        self.count += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    #[allow(dead_code)] // The windows are not merged yet, e.g. into the daily exposure report.
+    pub fn merge(&mut self, other: &DDSketch) {
+        for (key, count) in &other.positive {
+            *self.positive.entry(*key).or_insert(0) += count;
+        }
+        for (key, count) in &other.negative {
+            *self.negative.entry(*key).or_insert(0) += count;
+        }
+        self.zero += other.zero;
+        self.count += other.count;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = (q.clamp(0.0, 1.0) * (self.count - 1) as f64) as u64;
+        let mut seen = 0;
+        // From the smallest value: the negative buckets of the largest magnitude first, zero, then the positive buckets.
+        for (key, count) in self.negative.iter().rev() {
+            seen += count;
+            if seen > rank {
+                return Some((-self.value(*key)).clamp(self.min, self.max));
+            }
+        }
+        seen += self.zero;
+        if seen > rank {
+            return Some(0.0);
+        }
+        for (key, count) in &self.positive {
+            seen += count;
+            if seen > rank {
+                return Some(self.value(*key).clamp(self.min, self.max));
+            }
+        }
+        Some(self.max)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    width: f64,
+    buckets: BTreeMap<i64, u64>,
+}
+
+impl Histogram {
+    pub fn new(width: f64) -> Histogram {
+        Histogram {
+            width,
+            buckets: BTreeMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, value: f64) {
+        if value.is_finite() && self.width > 0.0 {
+            *self.buckets.entry((value / self.width).floor() as i64).or_insert(0) += 1;
+        }
+    }
+
+    pub fn buckets(&self) -> Vec<Bucket> {
+        self.buckets.iter().map(|(index, count)| Bucket {
+            lower: *index as f64 * self.width,
+            upper: (*index + 1) as f64 * self.width,
+            count: *count,
+        }).collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Bucket {
+    pub lower: f64, // inclusive
+    pub upper: f64, // exclusive
+    pub count: u64,
+}
+
+/// The distribution of a window in the structured output.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Statistics {
+    pub quantiles: BTreeMap<String, f64>,
+    pub min: f64,
+    pub max: f64,
+    pub histogram: Vec<Bucket>,
+}
+
+impl Statistics {
+    pub fn new(sketch: &DDSketch, histogram: &Histogram) -> Option<Statistics> {
+        let mut quantiles = BTreeMap::new();
+        for (name, q) in QUANTILES {
+            quantiles.insert(name.to_string(), sketch.quantile(q)?);
+        }
+        Some(Statistics {
+            quantiles,
+            min: sketch.min,
+            max: sketch.max,
+            histogram: histogram.buckets(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_relative(actual: Option<f64>, expected: f64) {
+        let actual = actual.unwrap();
+        assert!((actual - expected).abs() <= expected.abs() * RELATIVE_ACCURACY, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn test_quantiles() {
+        let mut sketch = DDSketch::new();
+        for value in 1..=1000 {
+            sketch.add(value as f64);
+        }
+        assert_relative(sketch.quantile(0.5), 500.0);
+        assert_relative(sketch.quantile(0.9), 900.0);
+        assert_relative(sketch.quantile(0.99), 990.0);
+        assert_eq!(sketch.quantile(0.0), Some(1.0));
+        assert_eq!(sketch.quantile(1.0), Some(1000.0));
+        assert_eq!(DDSketch::new().quantile(0.5), None);
+    }
+
+    #[test]
+    fn test_negative_values_and_zero() {
+        let mut sketch = DDSketch::new();
+        for value in [-20.0, -10.0, 0.0, 10.0, 20.0] {
+            sketch.add(value);
+        }
+        assert_relative(sketch.quantile(0.25), -10.0);
+        assert_eq!(sketch.quantile(0.5), Some(0.0));
+        assert_relative(sketch.quantile(0.75), 10.0);
+    }
+
+    #[test]
+    fn test_merge() {
+        let (mut first, mut second, mut whole) = (DDSketch::new(), DDSketch::new(), DDSketch::new());
+        for value in 400..1400 {
+            if value % 2 == 0 { first.add(value as f64) } else { second.add(value as f64) }
+            whole.add(value as f64);
+        }
+        first.merge(&second);
+        assert_eq!(first, whole);
+    }
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::new(100.0);
+        for value in [420.0, 480.0, 510.0, 1010.0] {
+            histogram.add(value);
+        }
+        assert_eq!(histogram.buckets(), vec![
+            Bucket { lower: 400.0, upper: 500.0, count: 2 },
+            Bucket { lower: 500.0, upper: 600.0, count: 1 },
+            Bucket { lower: 1000.0, upper: 1100.0, count: 1 },
+        ]);
+        let mut disabled = Histogram::new(0.0);
+        disabled.add(420.0);
+        assert!(disabled.buckets().is_empty());
+    }
+}
@@ -0,0 +1,204 @@
+/*
+    This is the transform module. It calibrates the readings and converts their units
+    before they are validated and fed to the processes.
+
+    The rules are selected by ENTITY/SENSOR/DATA_TYPE of the topic model, where `*` matches any
+    part and a bare DATA_TYPE matches the data type of every entity and sensor.
+    * Calibration: `<SELECTOR>:offset=<OFFSET>,gain=<GAIN>` (value * gain + offset)
+                   `<SELECTOR>:table=<RAW>/<TRUE>;<RAW>/<TRUE>;...` (piecewise-linear)
+    * Conversion:  `<SELECTOR>:<TO>` or `<SELECTOR>:<FROM>:<TO>` (FROM defaults to the unit of the topic model)
+*/
+use i483_sensors::SensorTopic;
+use crate::units;
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selector {
+    entity: Option<String>,
+    sensor: Option<String>,
+    data_type: Option<String>,
+}
+
+impl Selector {
+    pub fn parse(selector: &str) -> Option<Selector> {
+        let part = |part: &str| if part == "*" { None } else { Some(part.to_string()) };
+        let parts: Vec<&str> = selector.split('/').collect();
+        match parts.as_slice() {
+            [data_type] if !data_type.is_empty() => Some(Selector { entity: None, sensor: None, data_type: part(data_type) }),
+            [entity, sensor, data_type] => Some(Selector { entity: part(entity), sensor: part(sensor), data_type: part(data_type) }),
+            _ => None,
+        }
+    }
+
+    pub fn matches(&self, topic: &SensorTopic) -> bool {
+        self.entity.as_ref().is_none_or(|entity| *entity == topic.entity)
+            && self.sensor.as_ref().is_none_or(|sensor| sensor.eq_ignore_ascii_case(topic.sensor.name()))
+            && self.data_type.as_ref().is_none_or(|data_type| data_type.eq_ignore_ascii_case(topic.data_type.name()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Calibration {
+    Linear { gain: f64, offset: f64 },
+    Table(Vec<(f64, f64)>), // (raw, true) sorted by raw.
+}
+
+impl Calibration {
+    pub fn apply(&self, value: f64) -> f64 {
+        match self {
+            Calibration::Linear { gain, offset } => value * gain + offset,
+            Calibration::Table(points) => {
+                if points.len() == 1 {
+                    return value - points[0].0 + points[0].1;
+                }
+                // The segment which contains the value, or the first / last segment to extrapolate.
+                let i = points.windows(2)
+                    .position(|segment| value <= segment[1].0)
+                    .unwrap_or(points.len() - 2);
+                let ((x0, y0), (x1, y1)) = (points[i], points[i + 1]);
+                y0 + (value - x0) * (y1 - y0) / (x1 - x0)
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CalibrationRule {
+    pub selector: Selector,
+    pub calibration: Calibration,
+}
+
+impl CalibrationRule {
+    pub fn parse(rule: &str) -> Option<CalibrationRule> {
+        let (selector, calibration) = rule.split_once(':')?;
+        let selector = Selector::parse(selector)?;
+        let calibration = match calibration.strip_prefix("table=") {
+            Some(table) => {
+                let mut points = Vec::new();
+                for point in table.split(';') {
+                    let (raw, calibrated) = point.split_once('/')?;
+                    points.push((raw.trim().parse().ok()?, calibrated.trim().parse().ok()?));
+                }
+                points.sort_by(|a: &(f64, f64), b| a.0.total_cmp(&b.0));
+                if points.is_empty() || points.windows(2).any(|segment| segment[0].0 == segment[1].0) {
+                    return None;
+                }
+                Calibration::Table(points)
+            },
+            None => {
+                let (mut gain, mut offset) = (1.0, 0.0);
+                for param in calibration.split(',') {
+                    match param.split_once('=')? {
+                        ("gain", value) => gain = value.parse().ok()?,
+                        ("offset", value) => offset = value.parse().ok()?,
+                        _ => return None,
+                    }
+                }
+                Calibration::Linear { gain, offset }
+            },
+        };
+        Some(CalibrationRule { selector, calibration })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConversionRule {
+    pub selector: Selector,
+    pub from: Option<String>,
+    pub to: String,
+}
+
+impl ConversionRule {
+    pub fn parse(rule: &str) -> Option<ConversionRule> {
+        let parts: Vec<&str> = rule.split(':').collect();
+        let (selector, from, to) = match parts.as_slice() {
+            [selector, to] => (selector, None, to),
+            [selector, from, to] => (selector, Some(units::canonical(from)?.to_string()), to),
+            _ => return None,
+        };
+        Some(ConversionRule {
+            selector: Selector::parse(selector)?,
+            from,
+            to: units::canonical(to)?.to_string(),
+        })
+    }
+}
+
+pub struct Transformer {
+    calibrations: Vec<CalibrationRule>,
+    conversions: Vec<ConversionRule>,
+}
+
+impl Transformer {
+    pub fn new(calibrations: &[CalibrationRule], conversions: &[ConversionRule]) -> Transformer {
+        Transformer {
+            calibrations: calibrations.to_vec(),
+            conversions: conversions.to_vec(),
+        }
+    }
+
+    /// Calibrates the value in the unit of the device and converts it.
+    /// Returns the value and its unit (None if the unit is unknown).
+    pub fn apply(&self, topic: &str, value: f32) -> (f32, Option<String>) {
+        let sensor_topic = match SensorTopic::parse(topic) {
+            Some(sensor_topic) => sensor_topic,
+            None => return (value, None),
+        };
+        let mut value = value as f64;
+        for rule in self.calibrations.iter().filter(|rule| rule.selector.matches(&sensor_topic)) {
+            value = rule.calibration.apply(value);
+        }
+        let mut unit = sensor_topic.unit().map(|unit| unit.to_string());
+        if let Some(rule) = self.conversions.iter().find(|rule| rule.selector.matches(&sensor_topic)) {
+            let from = rule.from.clone().or(unit.clone());
+            match from.as_ref().and_then(|from| units::convert(value, from, &rule.to)) {
+                Some(converted) => {
+                    value = converted;
+                    unit = Some(rule.to.clone());
+                },
+                None => println!("Cannot convert {} from {:?} to {}", topic, from, rule.to),
+            }
+        }
+        (value as f32, unit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rules() {
+        let rule = CalibrationRule::parse("s2420010/BMP180/temperature:offset=-1.5").unwrap();
+        assert_eq!(rule.calibration, Calibration::Linear { gain: 1.0, offset: -1.5 });
+        let rule = CalibrationRule::parse("*/SCD41/co2:table=800/810;400/400").unwrap();
+        assert_eq!(rule.calibration, Calibration::Table(vec![(400.0, 400.0), (800.0, 810.0)]));
+        assert_eq!(CalibrationRule::parse("temperature:scale=2"), None);
+        assert_eq!(CalibrationRule::parse("a/b:offset=1"), None);
+        let rule = ConversionRule::parse("air_pressure:Pa:hPa").unwrap();
+        assert_eq!((rule.from, rule.to), (Some("Pa".to_string()), "hPa".to_string()));
+        assert_eq!(ConversionRule::parse("air_pressure:lx"), None);
+    }
+
+    #[test]
+    fn test_piecewise_linear_table() {
+        let calibration = Calibration::Table(vec![(400.0, 400.0), (800.0, 820.0), (1200.0, 1260.0)]);
+        assert_eq!(calibration.apply(600.0), 610.0);
+        assert_eq!(calibration.apply(1000.0), 1040.0);
+        assert_eq!(calibration.apply(1400.0), 1480.0);
+        assert_eq!(calibration.apply(200.0), 190.0);
+    }
+
+    #[test]
+    fn test_transform() {
+        let transformer = Transformer::new(
+            &[CalibrationRule::parse("s2420010/BMP180/temperature:offset=-1.5").unwrap()],
+            &[ConversionRule::parse("temperature:°F").unwrap(), ConversionRule::parse("air_pressure:Pa:hPa").unwrap()],
+        );
+        assert_eq!(transformer.apply("i483-sensors-s2420010-BMP180-temperature", 21.5), (68.0, Some("°F".to_string())));
+        assert_eq!(transformer.apply("i483-sensors-s2420011-BMP180-temperature", 20.0), (68.0, Some("°F".to_string())));
+        assert_eq!(transformer.apply("i483-sensors-s2420010-BMP180-air_pressure", 101325.0), (1013.25, Some("hPa".to_string())));
+        assert_eq!(transformer.apply("i483-sensors-s2420010-SCD41-co2", 812.0), (812.0, Some("ppm".to_string())));
+        assert_eq!(transformer.apply("other-topic", 1.0), (1.0, None));
+    }
+}
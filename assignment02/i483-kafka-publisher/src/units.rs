@@ -0,0 +1,93 @@
+/*
+    This is the units module. It converts the values between the units of the same quantity.
+    * Temperature: °C, °F, K
+    * Pressure: Pa, hPa, kPa, inHg
+    * Concentration: ppm, ppb, %
+*/
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Quantity {
+    Temperature,
+    Pressure,
+    Concentration,
+}
+
+/// Returns the canonical name of the unit, e.g. `degC` and `C` are `°C`.
+pub fn canonical(unit: &str) -> Option<&'static str> {
+    match unit.to_ascii_lowercase().as_str() {
+        "°c" | "c" | "degc" | "celsius" => Some("°C"),
+        "°f" | "f" | "degf" | "fahrenheit" => Some("°F"),
+        "k" | "kelvin" => Some("K"),
+        "pa" => Some("Pa"),
+        "hpa" | "mbar" => Some("hPa"),
+        "kpa" => Some("kPa"),
+        "inhg" => Some("inHg"),
+        "ppm" => Some("ppm"),
+        "ppb" => Some("ppb"),
+        "%" | "percent" => Some("%"),
+        _ => None,
+    }
+}
+
+// The quantity of the unit and the factors to its base unit (base = value * scale + shift).
+fn to_base(unit: &str) -> Option<(Quantity, f64, f64)> {
+    match canonical(unit)? {
+        "°C" => Some((Quantity::Temperature, 1.0, 273.15)),
+        "°F" => Some((Quantity::Temperature, 5.0 / 9.0, 273.15 - 32.0 * 5.0 / 9.0)),
+        "K" => Some((Quantity::Temperature, 1.0, 0.0)),
+        "Pa" => Some((Quantity::Pressure, 1.0, 0.0)),
+        "hPa" => Some((Quantity::Pressure, 100.0, 0.0)),
+        "kPa" => Some((Quantity::Pressure, 1000.0, 0.0)),
+        "inHg" => Some((Quantity::Pressure, 3386.389, 0.0)),
+        "ppm" => Some((Quantity::Concentration, 1.0, 0.0)),
+        "ppb" => Some((Quantity::Concentration, 0.001, 0.0)),
+        "%" => Some((Quantity::Concentration, 10000.0, 0.0)),
+        _ => None,
+    }
+}
+
+/// Converts the value, or returns None if the units are unknown or of different quantities.
+pub fn convert(value: f64, from: &str, to: &str) -> Option<f64> {
+    let (from_quantity, from_scale, from_shift) = to_base(from)?;
+    let (to_quantity, to_scale, to_shift) = to_base(to)?;
+    if from_quantity != to_quantity {
+        return None;
+    }
+    Some((value * from_scale + from_shift - to_shift) / to_scale)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: Option<f64>, expected: f64) {
+        assert!((actual.unwrap() - expected).abs() < 1e-6, "{:?} != {}", actual, expected);
+    }
+
+    #[test]
+    fn test_convert_temperature() {
+        assert_close(convert(100.0, "°C", "°F"), 212.0);
+        assert_close(convert(32.0, "F", "C"), 0.0);
+        assert_close(convert(0.0, "degC", "K"), 273.15);
+    }
+
+    #[test]
+    fn test_convert_pressure() {
+        assert_close(convert(101325.0, "Pa", "hPa"), 1013.25);
+        assert_close(convert(1013.25, "hPa", "inHg"), 29.921252);
+        assert_close(convert(1.0, "kPa", "Pa"), 1000.0);
+    }
+
+    #[test]
+    fn test_convert_concentration() {
+        assert_close(convert(1000.0, "ppm", "%"), 0.1);
+        assert_close(convert(1.0, "ppm", "ppb"), 1000.0);
+    }
+
+    #[test]
+    fn test_convert_invalid_units() {
+        assert_eq!(convert(1.0, "Pa", "°C"), None);
+        assert_eq!(convert(1.0, "lx", "lx"), None);
+    }
+}
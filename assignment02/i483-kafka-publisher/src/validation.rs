@@ -0,0 +1,116 @@
+/*
+    This is the validation module. It checks the readings before they are fed to the processes.
+    * Range: the reading must be in the measurement range of the sensor (from the topic model),
+             converted to the unit of the reading when it was converted by the transform stage.
+    * Stuck: the same reading must not repeat for N samples (disabled with 0).
+
+    The invalid readings are handled by the ValidationPolicy (see kafka::process).
+*/
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use i483_sensors::SensorTopic;
+use crate::units;
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Violation {
+    NotANumber,
+    OutOfRange { value: f32, min: f64, max: f64, unit: String },
+    Stuck { value: f32, repeats: usize },
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Violation::NotANumber => write!(f, "not a number"),
+            Violation::OutOfRange { value, min, max, unit } => write!(f, "out of range: {} not in [{}, {}] {}", value, min, max, unit),
+            Violation::Stuck { value, repeats } => write!(f, "stuck: {} repeated {} times", value, repeats),
+        }
+    }
+}
+
+pub struct Validator {
+    stuck_after: usize,
+    last_values: HashMap<String, (f32, usize)>, // topic -> (last value, repeats)
+}
+
+impl Validator {
+    pub fn new(stuck_after: usize) -> Validator {
+        Validator {
+            stuck_after,
+            last_values: HashMap::new(),
+        }
+    }
+
+    pub fn validate(&mut self, topic: &str, value: f32, unit: Option<&str>) -> Result<(), Violation> {
+        if !value.is_finite() {
+            return Err(Violation::NotANumber);
+        }
+        let repeats = match self.last_values.get_mut(topic) {
+            Some((last, repeats)) if *last == value => {
+                *repeats += 1;
+                *repeats
+            },
+            _ => {
+                self.last_values.insert(topic.to_string(), (value, 1));
+                1
+            },
+        };
+        if let Some(sensor_topic) = SensorTopic::parse(topic) {
+            if let Some((min, max)) = sensor_topic.valid_range() {
+                let model_unit = sensor_topic.unit().unwrap_or("");
+                let (min, max, unit) = match unit {
+                    Some(unit) if unit != model_unit => match (units::convert(min, model_unit, unit), units::convert(max, model_unit, unit)) {
+                        (Some(min), Some(max)) => (min, max, unit),
+                        _ => (min, max, model_unit),
+                    },
+                    _ => (min, max, model_unit),
+                };
+                if (value as f64) < min || (value as f64) > max {
+                    return Err(Violation::OutOfRange { value, min, max, unit: unit.to_string() });
+                }
+            }
+        }
+        if self.stuck_after > 0 && repeats >= self.stuck_after {
+            return Err(Violation::Stuck { value, repeats });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_range() {
+        let mut validator = Validator::new(0);
+        assert_eq!(validator.validate("i483-sensors-s2420010-SCD41-co2", 812.0, None), Ok(()));
+        assert!(matches!(validator.validate("i483-sensors-s2420010-SCD41-co2", 0.0, None), Err(Violation::OutOfRange { .. })));
+        assert!(matches!(validator.validate("i483-sensors-s2420010-SCD41-humidity", 101.0, None), Err(Violation::OutOfRange { .. })));
+        assert_eq!(validator.validate("i483-sensors-s2420010-BMP180-air_pressure", 101325.0, None), Ok(()));
+        assert!(matches!(validator.validate("i483-sensors-s2420010-BMP180-air_pressure", 1013.2, None), Err(Violation::OutOfRange { .. })));
+        assert_eq!(validator.validate("unknown-topic", 101325.0, None), Ok(()));
+        assert_eq!(validator.validate("unknown-topic", f32::NAN, None), Err(Violation::NotANumber));
+    }
+
+    #[test]
+    fn test_validate_stuck_value() {
+        let mut validator = Validator::new(3);
+        let topic = "i483-sensors-s2420010-SCD41-temperature";
+        assert_eq!(validator.validate(topic, 23.4, None), Ok(()));
+        assert_eq!(validator.validate(topic, 23.4, None), Ok(()));
+        assert_eq!(validator.validate(topic, 23.4, None), Err(Violation::Stuck { value: 23.4, repeats: 3 }));
+        assert_eq!(validator.validate(topic, 23.5, None), Ok(()));
+        assert_eq!(validator.validate("i483-sensors-s2420010-BMP180-temperature", 23.4, None), Ok(()));
+    }
+
+    #[test]
+    fn test_validate_range_in_converted_unit() {
+        let mut validator = Validator::new(0);
+        let topic = "i483-sensors-s2420010-BMP180-air_pressure";
+        assert_eq!(validator.validate(topic, 1013.2, Some("hPa")), Ok(()));
+        assert!(matches!(validator.validate(topic, 101325.0, Some("hPa")), Err(Violation::OutOfRange { .. })));
+        assert_eq!(validator.validate("i483-sensors-s2420010-SCD41-temperature", 300.0, Some("K")), Ok(()));
+    }
+}
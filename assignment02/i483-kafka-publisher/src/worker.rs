@@ -5,7 +5,17 @@
     The worker struct is used to create a worker instance that can be used to perform
     * Calculate the rolling average of a given age.
     * Calculate the threshold of a given value.
+    * Watch the silence of a stream (offline / online).
+    * Summarize the distribution (quantiles and histogram) of every window (see sketch.rs).
+    * Forecast the value ahead and alert before it crosses a threshold (see forecast.rs).
+    * Remove the spikes of a stream and report the replaced samples (see filter.rs).
+    * Compare the redundant sensors of an entity (see consistency.rs).
+    * Downsample a stream to a coarser resolution (see downsample.rs).
+    * Evaluate a user-defined expression (see expression.rs).
     * Returns a message to the caller when the task given to the worker is complete.
+
+    Every result is returned together with the window information (start, end, sample count
+    and the source offsets) so the output module can describe where the value came from.
 */
 use std::{fmt, thread};
 use std::collections::{HashMap, VecDeque};
@@ -13,14 +23,55 @@ use std::fmt::{Display, Formatter};
 use tokio::sync::mpsc::{channel, Sender, Receiver};
 use std::time::Duration;
 use chrono::{DateTime, TimeZone, Utc};
+use serde::Serialize;
 use uuid::Uuid;
+use i483_sensors::SensorTopic;
 use crate::cli::ProcessType;
+use crate::consistency::{ConsistencyCheck, Status};
+use crate::downsample::Downsampler;
+use crate::expression::{Context, Program};
+use crate::filter::HampelFilter;
+use crate::forecast::LinearTrend;
+use crate::sketch::{DDSketch, Histogram, Statistics};
 
 
 #[derive(Debug, Clone)]
 pub enum ProcessData {
     RollingAverage(f32),
     Threshold(f32),
+    Watchdog(f32), // 1.0 is online, 0.0 is offline.
+    Percentile(f32, Option<Statistics>), // The p50 and the statistics of the window, None in the inputs.
+    Forecast(f32),
+    ForecastAlert(f32), // The forecast which crosses the threshold, or 0.0 when it does not any more.
+    Hampel(f32), // The cleaned value.
+    HampelReplaced(f32), // The original value which is replaced.
+    Consistency(f32), // The reading, or the drift in the results.
+    ConsistencyStatus(Status),
+    Downsample(f32), // The reading, or the reduced value of a period in the results.
+    Expression(f32, HashMap<String, f32>), // The latest values of the topics for `latest`, empty in the results.
+}
+
+impl ProcessData {
+    pub fn new(process_type: &ProcessType, value: f32) -> ProcessData {
+        match process_type {
+            ProcessType::RollingAverage(_) => ProcessData::RollingAverage(value),
+            ProcessType::Threshold(_) => ProcessData::Threshold(value),
+            ProcessType::Watchdog { .. } => ProcessData::Watchdog(value),
+            ProcessType::Percentile { .. } => ProcessData::Percentile(value, None),
+            ProcessType::Forecast { .. } => ProcessData::Forecast(value),
+            ProcessType::Hampel { .. } => ProcessData::Hampel(value),
+            ProcessType::Consistency { .. } => ProcessData::Consistency(value),
+            ProcessType::Downsample { .. } => ProcessData::Downsample(value),
+            ProcessType::Expression { .. } => ProcessData::Expression(value, HashMap::new()),
+        }
+    }
+
+    pub fn statistics(&self) -> Option<&Statistics> {
+        match self {
+            ProcessData::Percentile(_, statistics) => statistics.as_ref(),
+            _ => None,
+        }
+    }
 }
 
 impl Display for ProcessData {
@@ -28,14 +79,95 @@ impl Display for ProcessData {
         match self {
             ProcessData::RollingAverage(value) => write!(f, "Rolling average: {}", value),
             ProcessData::Threshold(value) => write!(f, "Threshold: {}", value),
+            ProcessData::Watchdog(value) => write!(f, "Watchdog: {}", value),
+            ProcessData::Percentile(value, _) => write!(f, "Percentile: {}", value),
+            ProcessData::Forecast(value) => write!(f, "Forecast: {}", value),
+            ProcessData::ForecastAlert(value) => write!(f, "Forecast alert: {}", value),
+            ProcessData::Hampel(value) => write!(f, "Hampel: {}", value),
+            ProcessData::HampelReplaced(value) => write!(f, "Hampel replaced: {}", value),
+            ProcessData::Consistency(value) => write!(f, "Consistency: {}", value),
+            ProcessData::ConsistencyStatus(status) => write!(f, "Consistency status: {}", status),
+            ProcessData::Downsample(value) => write!(f, "Downsample: {}", value),
+            ProcessData::Expression(value, _) => write!(f, "Expression: {}", value),
+        }
+    }
+}
+
+/// Where a single sample came from.
+#[derive(Debug, Clone)]
+pub struct SourceRecord {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+    pub timestamp: DateTime<Utc>,
+    pub correlation_id: Option<String>,
+    pub unit: Option<String>, // The unit after the transform stage.
+}
+
+/// The offsets of a topic partition which are contributed to a window.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SourceRange {
+    pub topic: String,
+    pub partition: i32,
+    pub first_offset: i64,
+    pub last_offset: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct WindowInfo {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub count: u64,
+    pub sources: Vec<SourceRange>,
+    pub correlation_id: Option<String>,
+    pub unit: Option<String>,
+}
+
+impl WindowInfo {
+    pub fn new(start: DateTime<Utc>) -> WindowInfo {
+        WindowInfo {
+            start,
+            end: start,
+            count: 0,
+            sources: Vec::new(),
+            correlation_id: None,
+            unit: None,
+        }
+    }
+
+    pub fn record(&mut self, source: &SourceRecord) {
+        self.count += 1;
+        if source.timestamp > self.end {
+            self.end = source.timestamp;
+        }
+        if source.correlation_id.is_some() {
+            self.correlation_id = source.correlation_id.clone();
+        }
+        if source.unit.is_some() {
+            self.unit = source.unit.clone();
+        }
+        match self.sources.iter_mut().find(|r| r.topic == source.topic && r.partition == source.partition) {
+            Some(range) => {
+                range.first_offset = range.first_offset.min(source.offset);
+                range.last_offset = range.last_offset.max(source.offset);
+            },
+            None => {
+                self.sources.push(SourceRange {
+                    topic: source.topic.clone(),
+                    partition: source.partition,
+                    first_offset: source.offset,
+                    last_offset: source.offset,
+                });
+            },
         }
     }
 }
 
 pub enum ActorMessage {
-    FeedData(Uuid, ProcessData),
-    Updated(Uuid, ProcessData),
-    Finished(Uuid, ProcessData),
+    FeedData(Uuid, ProcessData, SourceRecord),
+    Updated(Uuid, ProcessData, WindowInfo),
+    Finished(Uuid, ProcessData, WindowInfo),
+    Tick,
     AddActor(Uuid, Sender<ActorMessage>),
     RemoveActor(Uuid),
     GetActors(),
@@ -45,7 +177,7 @@ pub enum ActorMessage {
 trait Actor {
     fn send_message(&self, message: ActorMessage);
     fn receive_message(&mut self);
-    fn compute(&mut self, data: ProcessData);
+    fn compute(&mut self, data: ProcessData, source: SourceRecord);
     fn fetch(&self) -> f32;
     fn send_last_will(&mut self);
 }
@@ -117,7 +249,7 @@ impl Actor for ManagerActor {
         }
     }
 
-    fn compute(&mut self, data: ProcessData) {
+    fn compute(&mut self, data: ProcessData, _source: SourceRecord) {
         println!("Manager actor {} received data: {}", self.id, &data);
     }
 
@@ -131,6 +263,32 @@ impl Actor for ManagerActor {
 }
 
 
+// The state of the processor, which only the actors of its process type hold.
+// The rolling average, the threshold and the watchdog only use the common fields of the actor.
+enum ProcessorState {
+    Common,
+    Percentile { sketch: DDSketch, histogram: Histogram },
+    Forecast(LinearTrend),
+    Hampel(HampelFilter),
+    Consistency(ConsistencyCheck),
+    Downsample(Downsampler<WindowInfo>),
+    Expression { program: Program, prev: Option<f32> },
+}
+
+impl ProcessorState {
+    fn new(process_type: &ProcessType) -> ProcessorState {
+        match process_type {
+            ProcessType::Percentile { bucket_width, .. } => ProcessorState::Percentile { sketch: DDSketch::new(), histogram: Histogram::new(*bucket_width as f64) },
+            ProcessType::Forecast { window, .. } => ProcessorState::Forecast(LinearTrend::new(*window)),
+            ProcessType::Hampel { window, n_sigmas } => ProcessorState::Hampel(HampelFilter::new(*window as usize, *n_sigmas as f64)),
+            ProcessType::Consistency { tolerance_milli, timeout } => ProcessorState::Consistency(ConsistencyCheck::new(*tolerance_milli as f64 / 1000.0, *timeout)),
+            ProcessType::Downsample { reducer, .. } => ProcessorState::Downsample(Downsampler::new(reducer.clone())),
+            ProcessType::Expression { program, .. } => ProcessorState::Expression { program: program.clone(), prev: None },
+            ProcessType::RollingAverage(_) | ProcessType::Threshold(_) | ProcessType::Watchdog { .. } => ProcessorState::Common,
+        }
+    }
+}
+
 pub(crate) struct ComputeActor {
     id: Uuid,
     dead_at: DateTime<Utc>,
@@ -142,7 +300,12 @@ pub(crate) struct ComputeActor {
     counter: u64,
     result: ProcessData,
     rolling_stack: VecDeque<f32>,
-    rolling_counter: VecDeque<u64> // I know it's too ugly, but I'm running out of time
+    rolling_counter: VecDeque<u64>, // I know it's too ugly, but I'm running out of time
+    rolling_windows: VecDeque<WindowInfo>,
+    last_window: WindowInfo,
+    last_seen: DateTime<Utc>,
+    window_end: DateTime<Utc>, // The end of the current window of the percentile and the downsample.
+    state: ProcessorState,
 }
 
 impl ComputeActor {
@@ -161,11 +324,26 @@ impl ComputeActor {
         let result = match process_type {
             ProcessType::RollingAverage(value) => ProcessData::RollingAverage(0.0),
             ProcessType::Threshold(value) => ProcessData::Threshold(0.0),
+            ProcessType::Watchdog { .. } => ProcessData::Watchdog(1.0),
+            ProcessType::Percentile { .. } => ProcessData::Percentile(0.0, None),
+            ProcessType::Forecast { .. } => ProcessData::Forecast(0.0),
+            ProcessType::Hampel { .. } => ProcessData::Hampel(0.0),
+            ProcessType::Consistency { .. } => ProcessData::Consistency(0.0),
+            ProcessType::Downsample { .. } => ProcessData::Downsample(0.0),
+            ProcessType::Expression { .. } => ProcessData::Expression(0.0, HashMap::new()),
+        };
+        let (window_start, window_end) = match process_type {
+            ProcessType::Percentile { duration, .. } => tumbling_window(Utc::now(), duration),
+            ProcessType::Downsample { period, .. } => tumbling_window(Utc::now(), period),
+            _ => (Utc::now(), Utc::now()),
         };
+        let state = ProcessorState::new(&process_type);
         let mut rolling_stack = VecDeque::new();
         rolling_stack.push_back(0.0);
         let mut rolling_counter = VecDeque::new();
         rolling_counter.push_back(0);
+        let mut rolling_windows = VecDeque::new();
+        rolling_windows.push_back(WindowInfo::new(Utc::now()));
         ComputeActor {
             id,
             dead_at,
@@ -177,7 +355,62 @@ impl ComputeActor {
             counter: 0,
             result,
             rolling_stack,
-            rolling_counter
+            rolling_counter,
+            rolling_windows,
+            last_window: WindowInfo::new(window_start),
+            last_seen: Utc::now(),
+            window_end,
+            state,
+        }
+    }
+
+    // Emits the statistics (or the downsampled value) when the window is over, and starts the next window.
+    fn check_window(&mut self) {
+        let duration = match self.process_type {
+            ProcessType::Percentile { duration, .. } => duration,
+            ProcessType::Downsample { period, .. } => period,
+            _ => return,
+        };
+        if Utc::now() < self.window_end {
+            return;
+        }
+        let mut window = self.last_window.clone();
+        window.end = self.window_end;
+        let emitted = match (&mut self.state, &self.process_type) {
+            (ProcessorState::Percentile { sketch, histogram }, ProcessType::Percentile { bucket_width, .. }) => {
+                let emitted = if sketch.count() > 0 {
+                    let statistics = Statistics::new(sketch, histogram).unwrap();
+                    Some((ProcessData::Percentile(statistics.quantiles["p50"] as f32, Some(statistics)), window))
+                } else {
+                    None
+                };
+                *sketch = DDSketch::new();
+                *histogram = Histogram::new(*bucket_width as f64);
+                emitted
+            },
+            // The window of LTTB is the previous one, since a period is picked after the next one.
+            (ProcessorState::Downsample(downsampler), _) => downsampler.close(window).map(|(window, value)| (ProcessData::Downsample(value as f32), window)),
+            _ => None,
+        };
+        if let Some((data, window)) = emitted {
+            self.result = data.clone();
+            self.send_message(ActorMessage::Updated(self.id, data, window));
+        }
+        let (start, end) = tumbling_window(Utc::now(), duration);
+        self.last_window = WindowInfo::new(start);
+        self.window_end = end;
+    }
+
+    fn check_silence(&mut self) {
+        if let ProcessType::Watchdog { period, multiple, .. } = self.process_type {
+            if self.counter == 0 && self.last_seen + Duration::from_secs(period * multiple) < Utc::now() {
+                println!("Actor {} has not received data since {}", self.id, self.last_seen);
+                self.counter = 1;
+                self.result = ProcessData::Watchdog(0.0);
+                let mut window = self.last_window.clone();
+                window.end = Utc::now();
+                self.send_message(ActorMessage::Updated(self.id, ProcessData::Watchdog(0.0), window));
+            }
         }
     }
 }
@@ -201,19 +434,27 @@ impl Actor for ComputeActor {
             match self.receiver.blocking_recv() {
                 Some(message) => {
                     match message {
-                        ActorMessage::FeedData(_, data) => {
-                            self.compute(data);
+                        ActorMessage::FeedData(_, data, source) => {
+                            self.compute(data, source);
+                        },
+                        ActorMessage::Tick => {
+                            self.check_silence();
+                            self.check_window();
                         },
                         _ => {},
                     }
                 },
-                None => {}
+                None => {
+                    println!("Actor {} is evicted. process {:?}", self.id, self.process_type);
+                    break;
+                }
             }
             if next_rolling_average_start < Utc::now() {
                 println!("Actor {} is resetting rolling average", self.id);
                 next_rolling_average_start = Utc::now() + Duration::from_secs(self.lifespan);
                 self.rolling_stack.push_back(0.0);
                 self.rolling_counter.push_back(0);
+                self.rolling_windows.push_back(WindowInfo::new(Utc::now()));
             }
             if self.dead_at < Utc::now() {
                 if self.rolling_stack.len() > 1 {
@@ -223,7 +464,7 @@ impl Actor for ComputeActor {
         }
     }
 
-    fn compute(&mut self, data: ProcessData) {
+    fn compute(&mut self, data: ProcessData, source: SourceRecord) {
         println!("Actor {} received data: {}", self.id, &data);
         match data {
             ProcessData::RollingAverage(value) => {
@@ -240,6 +481,7 @@ impl Actor for ComputeActor {
                                 let new_avg = previous * (count - 1) as f32 / count as f32 + value / count as f32;
                                 self.rolling_stack[i] = new_avg;
                             }
+                            self.rolling_windows[i].record(&source);
                             println!("Actor {} rolling average {}: {}/{}", self.id, i, self.rolling_stack[i], count);
                         }
                     },
@@ -250,23 +492,151 @@ impl Actor for ComputeActor {
                 match self.process_type {
                     ProcessType::Threshold(baseline) => {
                         println!("Actor {} is computing threshold", self.id);
+                        let mut window = WindowInfo::new(source.timestamp);
+                        window.record(&source);
                         if value >= baseline as f32 {
                             if self.counter == 0 {
                                 self.counter = 1;
                                 self.result = ProcessData::Threshold(value);
-                                self.send_message(ActorMessage::Updated(self.id, ProcessData::Threshold(value)));
+                                self.last_window = window.clone();
+                                self.send_message(ActorMessage::Updated(self.id, ProcessData::Threshold(value), window));
                             }
                         } else {
                             if self.counter == 1 {
                                 self.counter = 0;
                                 self.result = ProcessData::Threshold(0.0);
-                                self.send_message(ActorMessage::Updated(self.id, ProcessData::Threshold(0.0)));
+                                self.last_window = window.clone();
+                                self.send_message(ActorMessage::Updated(self.id, ProcessData::Threshold(0.0), window));
                             }
                         }
                     },
                     _ => {},
                 }
             },
+            ProcessData::Watchdog(_) => {
+                let mut window = WindowInfo::new(source.timestamp);
+                window.record(&source);
+                self.last_seen = Utc::now();
+                self.last_window = window.clone();
+                if self.counter == 1 {
+                    println!("Actor {} is receiving data again", self.id);
+                    self.counter = 0;
+                    self.result = ProcessData::Watchdog(1.0);
+                    self.send_message(ActorMessage::Updated(self.id, ProcessData::Watchdog(1.0), window));
+                }
+            },
+            ProcessData::Percentile(value, _) => {
+                self.check_window();
+                if let ProcessorState::Percentile { sketch, histogram } = &mut self.state {
+                    sketch.add(value as f64);
+                    histogram.add(value as f64);
+                }
+                self.last_window.record(&source);
+            },
+            ProcessData::Forecast(value) => {
+                if let ProcessType::Forecast { horizon, threshold, .. } = self.process_type {
+                    let predicted = match &mut self.state {
+                        ProcessorState::Forecast(trend) => {
+                            trend.add(source.timestamp, value as f64);
+                            trend.predict(horizon)
+                        },
+                        _ => None,
+                    };
+                    let predicted = match predicted {
+                        Some(predicted) => predicted as f32,
+                        None => return,
+                    };
+                    let mut window = WindowInfo::new(source.timestamp);
+                    window.record(&source);
+                    self.result = ProcessData::Forecast(predicted);
+                    self.last_window = window.clone();
+                    self.send_message(ActorMessage::Updated(self.id, ProcessData::Forecast(predicted), window.clone()));
+                    if threshold == 0 {
+                        return;
+                    }
+                    // Same as the threshold process, but for the forecast.
+                    if predicted >= threshold as f32 && self.counter == 0 {
+                        self.counter = 1;
+                        self.send_message(ActorMessage::Updated(self.id, ProcessData::ForecastAlert(predicted), window));
+                    } else if predicted < threshold as f32 && self.counter == 1 {
+                        self.counter = 0;
+                        self.send_message(ActorMessage::Updated(self.id, ProcessData::ForecastAlert(0.0), window));
+                    }
+                }
+            },
+            ProcessData::ForecastAlert(_) => {},
+            ProcessData::Hampel(value) => {
+                let (cleaned, replaced) = match &mut self.state {
+                    ProcessorState::Hampel(filter) => filter.filter(value as f64),
+                    _ => return,
+                };
+                let mut window = WindowInfo::new(source.timestamp);
+                window.record(&source);
+                if replaced {
+                    println!("Actor {} replaced {} with {}", self.id, value, cleaned);
+                    self.send_message(ActorMessage::Updated(self.id, ProcessData::HampelReplaced(value), window.clone()));
+                }
+                self.result = ProcessData::Hampel(cleaned as f32);
+                self.last_window = window.clone();
+                self.send_message(ActorMessage::Updated(self.id, ProcessData::Hampel(cleaned as f32), window));
+            },
+            ProcessData::HampelReplaced(_) => {},
+            ProcessData::Consistency(value) => {
+                let sensor = match SensorTopic::parse(&source.topic) {
+                    Some(sensor_topic) => sensor_topic.sensor.to_string(),
+                    None => source.topic.clone(),
+                };
+                let (drift, status) = match &mut self.state {
+                    ProcessorState::Consistency(consistency) => consistency.update(&sensor, value as f64, source.timestamp),
+                    _ => return,
+                };
+                let mut window = WindowInfo::new(source.timestamp);
+                window.record(&source);
+                if let Some(drift) = drift {
+                    self.result = ProcessData::Consistency(drift as f32);
+                    self.last_window = window.clone();
+                    self.send_message(ActorMessage::Updated(self.id, ProcessData::Consistency(drift as f32), window.clone()));
+                }
+                if let Some(status) = status {
+                    println!("Actor {} consistency is {}", self.id, status);
+                    self.send_message(ActorMessage::Updated(self.id, ProcessData::ConsistencyStatus(status), window));
+                }
+            },
+            ProcessData::ConsistencyStatus(_) => {},
+            ProcessData::Downsample(value) => {
+                self.check_window();
+                if let ProcessorState::Downsample(downsampler) = &mut self.state {
+                    downsampler.add(source.timestamp, value as f64);
+                }
+                self.last_window.record(&source);
+            },
+            ProcessData::Expression(value, latest) => {
+                let (program, prev) = match &mut self.state {
+                    ProcessorState::Expression { program, prev } => (program, prev),
+                    _ => return,
+                };
+                self.counter += 1;
+                let context = Context {
+                    value: value as f64,
+                    prev: prev.unwrap_or(value) as f64,
+                    count: self.counter,
+                    latest: &latest,
+                };
+                let result = program.evaluate(&context);
+                *prev = Some(value);
+                let mut window = WindowInfo::new(source.timestamp);
+                window.record(&source);
+                match result {
+                    Ok(emitted) => {
+                        for value in emitted {
+                            self.result = ProcessData::Expression(value as f32, HashMap::new());
+                            self.last_window = window.clone();
+                            self.send_message(ActorMessage::Updated(self.id, ProcessData::Expression(value as f32, HashMap::new()), window.clone()));
+                        }
+                    },
+                    Err(e) => println!("Actor {} failed to evaluate the expression: {}", self.id, e),
+                }
+            },
         }
     }
 
@@ -278,6 +648,30 @@ impl Actor for ComputeActor {
             ProcessData::Threshold(value) => {
                 value
             },
+            ProcessData::Watchdog(value) => {
+                value
+            },
+            ProcessData::Percentile(value, _) => {
+                value
+            },
+            ProcessData::Forecast(value) | ProcessData::ForecastAlert(value) => {
+                value
+            },
+            ProcessData::Hampel(value) | ProcessData::HampelReplaced(value) => {
+                value
+            },
+            ProcessData::Consistency(value) => {
+                value
+            },
+            ProcessData::ConsistencyStatus(ref status) => {
+                if *status == Status::Ok { 1.0 } else { 0.0 }
+            },
+            ProcessData::Downsample(value) => {
+                value
+            },
+            ProcessData::Expression(value, _) => {
+                value
+            },
         }
     }
 
@@ -287,15 +681,47 @@ impl Actor for ComputeActor {
             ProcessData::RollingAverage(_) => {
                 let value = self.rolling_stack.pop_front().unwrap();
                 let _ = self.rolling_counter.pop_front().unwrap();
-                self.send_message(ActorMessage::Finished(self.id, ProcessData::RollingAverage(value)));
+                let window = self.rolling_windows.pop_front().unwrap();
+                self.send_message(ActorMessage::Finished(self.id, ProcessData::RollingAverage(value), window));
             },
             ProcessData::Threshold(value) => {
-                self.send_message(ActorMessage::Finished(self.id, ProcessData::Threshold(value)));
+                self.send_message(ActorMessage::Finished(self.id, ProcessData::Threshold(value), self.last_window.clone()));
+            },
+            ProcessData::Watchdog(value) => {
+                self.send_message(ActorMessage::Finished(self.id, ProcessData::Watchdog(value), self.last_window.clone()));
+            },
+            ProcessData::Percentile(value, ref statistics) => {
+                self.send_message(ActorMessage::Finished(self.id, ProcessData::Percentile(value, statistics.clone()), self.last_window.clone()));
+            },
+            ProcessData::Forecast(value) | ProcessData::ForecastAlert(value) => {
+                self.send_message(ActorMessage::Finished(self.id, ProcessData::Forecast(value), self.last_window.clone()));
+            },
+            ProcessData::Hampel(value) | ProcessData::HampelReplaced(value) => {
+                self.send_message(ActorMessage::Finished(self.id, ProcessData::Hampel(value), self.last_window.clone()));
+            },
+            ProcessData::Consistency(value) => {
+                self.send_message(ActorMessage::Finished(self.id, ProcessData::Consistency(value), self.last_window.clone()));
+            },
+            ProcessData::ConsistencyStatus(ref status) => {
+                self.send_message(ActorMessage::Finished(self.id, ProcessData::ConsistencyStatus(status.clone()), self.last_window.clone()));
+            },
+            ProcessData::Downsample(value) => {
+                self.send_message(ActorMessage::Finished(self.id, ProcessData::Downsample(value), self.last_window.clone()));
+            },
+            ProcessData::Expression(value, _) => {
+                self.send_message(ActorMessage::Finished(self.id, ProcessData::Expression(value, HashMap::new()), self.last_window.clone()));
             },
         }
     }
 }
 
+// The window of duration seconds which contains the time, aligned to the epoch.
+fn tumbling_window(time: DateTime<Utc>, duration: u64) -> (DateTime<Utc>, DateTime<Utc>) {
+    let duration = duration.max(1) as i64;
+    let start = time.timestamp() - time.timestamp().rem_euclid(duration);
+    (Utc.timestamp_opt(start, 0).unwrap(), Utc.timestamp_opt(start + duration, 0).unwrap())
+}
+
 pub fn create_actor(lifespan: u64, process_type: ProcessType, main_sender: &Sender<ActorMessage>) -> (Uuid, Sender<ActorMessage>) {
     let (sender, receiver) = channel(100);
     let id = Uuid::new_v4();
@@ -305,3 +731,159 @@ pub fn create_actor(lifespan: u64, process_type: ProcessType, main_sender: &Send
     });
     (id, sender)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::downsample::Reducer;
+
+    fn source(offset: i64) -> SourceRecord {
+        SourceRecord {
+            topic: "i483-sensors-s2420010-SCD41-co2".to_string(),
+            partition: 0,
+            offset,
+            timestamp: Utc::now(),
+            correlation_id: None,
+            unit: None,
+        }
+    }
+
+    fn test_actor(process_type: ProcessType) -> (ComputeActor, Receiver<ActorMessage>) {
+        let (main_sender, main_receiver) = channel(10);
+        let (_, receiver) = channel(10);
+        (ComputeActor::new(Uuid::new_v4(), 0, process_type, main_sender, receiver), main_receiver)
+    }
+
+    #[test]
+    fn test_watchdog_offline_and_online() {
+        let (mut actor, mut main_receiver) = test_actor(ProcessType::Watchdog { period: 15, multiple: 3, per_entity: false });
+        actor.compute(ProcessData::Watchdog(812.0), source(1));
+        actor.check_silence();
+        assert!(main_receiver.try_recv().is_err());
+
+        actor.last_seen = Utc::now() - Duration::from_secs(46);
+        actor.check_silence();
+        match main_receiver.try_recv() {
+            Ok(ActorMessage::Updated(_, ProcessData::Watchdog(value), window)) => {
+                assert_eq!(value, 0.0);
+                assert_eq!(window.sources[0].last_offset, 1);
+            },
+            _ => panic!("offline is not sent"),
+        }
+        actor.check_silence();
+        assert!(main_receiver.try_recv().is_err());
+
+        actor.compute(ProcessData::Watchdog(815.0), source(2));
+        match main_receiver.try_recv() {
+            Ok(ActorMessage::Updated(_, ProcessData::Watchdog(value), _)) => assert_eq!(value, 1.0),
+            _ => panic!("online is not sent"),
+        }
+    }
+
+    #[test]
+    fn test_percentile_window() {
+        let (mut actor, mut main_receiver) = test_actor(ProcessType::Percentile { duration: 300, bucket_width: 100 });
+        for (offset, value) in [450.0, 820.0, 810.0, 1250.0].into_iter().enumerate() {
+            actor.compute(ProcessData::Percentile(value, None), source(offset as i64));
+        }
+        actor.check_window();
+        assert!(main_receiver.try_recv().is_err());
+
+        actor.window_end = Utc::now() - Duration::from_secs(1);
+        actor.check_window();
+        match main_receiver.try_recv() {
+            Ok(ActorMessage::Updated(_, ProcessData::Percentile(p50, Some(statistics)), window)) => {
+                assert!((p50 - 810.0).abs() < 810.0 * 0.01);
+                assert_eq!(statistics.max, 1250.0);
+                assert_eq!(statistics.histogram.len(), 3);
+                assert_eq!(window.count, 4);
+            },
+            _ => panic!("statistics are not sent"),
+        }
+        assert!(actor.window_end > Utc::now());
+        assert!(matches!(&actor.state, ProcessorState::Percentile { sketch, .. } if sketch.count() == 0));
+    }
+
+    #[test]
+    fn test_forecast_alert_before_crossing() {
+        let (mut actor, mut main_receiver) = test_actor(ProcessType::Forecast { horizon: 600, threshold: 1000, window: 600 });
+        let start = Utc::now();
+        for (i, value) in [800.0, 805.0, 810.0].into_iter().enumerate() {
+            let mut source = source(i as i64);
+            source.timestamp = start + chrono::Duration::seconds(i as i64 * 15);
+            actor.compute(ProcessData::Forecast(value), source);
+        }
+        match main_receiver.try_recv() {
+            Ok(ActorMessage::Updated(_, ProcessData::Forecast(predicted), _)) => assert!((predicted - 1010.0).abs() < 0.01),
+            _ => panic!("forecast is not sent"),
+        }
+        match main_receiver.try_recv() {
+            Ok(ActorMessage::Updated(_, ProcessData::ForecastAlert(predicted), _)) => assert!(predicted > 1000.0),
+            _ => panic!("alert is not sent"),
+        }
+        assert!(main_receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_hampel_reports_replaced_sample() {
+        let (mut actor, mut main_receiver) = test_actor(ProcessType::Hampel { window: 5, n_sigmas: 3 });
+        for (offset, value) in [812.0, 815.0, 810.0, 818.0, 813.0].into_iter().enumerate() {
+            actor.compute(ProcessData::Hampel(value), source(offset as i64));
+            assert!(matches!(main_receiver.try_recv(), Ok(ActorMessage::Updated(_, ProcessData::Hampel(_), _))));
+        }
+        actor.compute(ProcessData::Hampel(5000.0), source(5));
+        match main_receiver.try_recv() {
+            Ok(ActorMessage::Updated(_, ProcessData::HampelReplaced(value), window)) => {
+                assert_eq!(value, 5000.0);
+                assert_eq!(window.sources[0].last_offset, 5);
+            },
+            _ => panic!("replaced sample is not reported"),
+        }
+        match main_receiver.try_recv() {
+            Ok(ActorMessage::Updated(_, ProcessData::Hampel(value), _)) => assert_eq!(value, 813.0),
+            _ => panic!("cleaned value is not sent"),
+        }
+    }
+
+    #[test]
+    fn test_consistency_pairs_sensors() {
+        let (mut actor, mut main_receiver) = test_actor(ProcessType::Consistency { tolerance_milli: 1500, timeout: 60 });
+        let mut bmp180 = source(1);
+        bmp180.topic = "i483-sensors-s2420010-BMP180-temperature".to_string();
+        let mut scd41 = source(2);
+        scd41.topic = "i483-sensors-s2420010-SCD41-temperature".to_string();
+        actor.compute(ProcessData::Consistency(24.0), bmp180);
+        assert!(main_receiver.try_recv().is_err());
+        actor.compute(ProcessData::Consistency(26.0), scd41);
+        match main_receiver.try_recv() {
+            Ok(ActorMessage::Updated(_, ProcessData::Consistency(drift), _)) => assert_eq!(drift, 2.0),
+            _ => panic!("drift is not sent"),
+        }
+        match main_receiver.try_recv() {
+            Ok(ActorMessage::Updated(_, ProcessData::ConsistencyStatus(status), _)) => assert_eq!(status, Status::Drift),
+            _ => panic!("status is not sent"),
+        }
+    }
+
+    #[test]
+    fn test_downsample_window() {
+        let (mut actor, mut main_receiver) = test_actor(ProcessType::Downsample { period: 300, reducer: Reducer::Max });
+        for (offset, value) in [812.0, 830.0, 815.0].into_iter().enumerate() {
+            actor.compute(ProcessData::Downsample(value), source(offset as i64));
+        }
+        actor.check_window();
+        assert!(main_receiver.try_recv().is_err());
+        actor.window_end = Utc::now() - Duration::from_secs(1);
+        actor.check_window();
+        match main_receiver.try_recv() {
+            Ok(ActorMessage::Updated(_, ProcessData::Downsample(value), window)) => {
+                assert_eq!(value, 830.0);
+                assert_eq!(window.count, 3);
+            },
+            _ => panic!("downsampled value is not sent"),
+        }
+        actor.window_end = Utc::now() - Duration::from_secs(1);
+        actor.check_window();
+        assert!(main_receiver.try_recv().is_err());
+    }
+}
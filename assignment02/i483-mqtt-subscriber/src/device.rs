@@ -0,0 +1,160 @@
+/*
+    This is the device module. It is the typed request/response of the node commands over MQTT v5.
+    * Request: a JSON document such as {"command": "interval", "seconds": 30}, published with QoS 1 to
+      i483/devices/<ENTITY>/request/<COMMAND>, with the response topic, the correlation data and the message expiry of the timeout.
+    * Response: the node publishes a JSON document to the response topic with the same correlation data.
+      {"status": "error", "error": "..."} is a failure, and the other fields depend on the command.
+    * Commands: self-test (the SCD41 self-test, which takes 10 s), recalibrate <ppm> (the SCD41 forced recalibration
+      to the reference CO2), interval <seconds> (the publish interval of mqtt_pub.py), and firmware (the version information).
+*/
+use std::fmt::{self, Display, Formatter};
+use std::time::Duration;
+use serde_json::{json, Value};
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceCommand {
+    SelfTest,
+    Recalibrate { co2: u16 }, // The reference CO2 in ppm.
+    SetInterval { seconds: u64 },
+    FirmwareInfo,
+}
+
+impl DeviceCommand {
+    /// Parses the command and its value, e.g. `recalibrate 420`.
+    pub fn parse(command: &str, value: Option<&str>) -> Option<DeviceCommand> {
+        match (command.to_ascii_lowercase().as_str(), value) {
+            ("self-test", None) => Some(DeviceCommand::SelfTest),
+            ("recalibrate", Some(co2)) => Some(DeviceCommand::Recalibrate { co2: co2.parse().ok()? }),
+            ("interval", Some(seconds)) => match seconds.parse() {
+                Ok(seconds) if seconds > 0 => Some(DeviceCommand::SetInterval { seconds }),
+                _ => None,
+            },
+            ("firmware", None) => Some(DeviceCommand::FirmwareInfo),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DeviceCommand::SelfTest => "self-test",
+            DeviceCommand::Recalibrate { .. } => "recalibrate",
+            DeviceCommand::SetInterval { .. } => "interval",
+            DeviceCommand::FirmwareInfo => "firmware",
+        }
+    }
+
+    /// The timeout without --timeout, which covers the self-test of the SCD41.
+    pub fn default_timeout(&self) -> Duration {
+        match self {
+            DeviceCommand::SelfTest => Duration::from_secs(20),
+            _ => Duration::from_secs(5),
+        }
+    }
+
+    pub fn payload(&self) -> Value {
+        match self {
+            DeviceCommand::Recalibrate { co2 } => json!({"command": self.name(), "co2": co2}),
+            DeviceCommand::SetInterval { seconds } => json!({"command": self.name(), "seconds": seconds}),
+            DeviceCommand::SelfTest | DeviceCommand::FirmwareInfo => json!({"command": self.name()}),
+        }
+    }
+
+    /// Parses the response of the node to the command.
+    pub fn parse_reply(&self, payload: &[u8]) -> Result<DeviceReply, String> {
+        let reply: Value = serde_json::from_slice(payload).map_err(|e| format!("invalid reply: {}", e))?;
+        if reply["status"] == "error" {
+            return Ok(DeviceReply::Error(reply["error"].as_str().unwrap_or("unknown error").to_string()));
+        }
+        let field = |name: &str| reply.get(name).filter(|value| !value.is_null()).ok_or(format!("the reply has no `{}`: {}", name, reply));
+        match self {
+            DeviceCommand::SelfTest => Ok(DeviceReply::SelfTest { malfunction: field("malfunction")?.as_bool().ok_or("`malfunction` is not a boolean")? }),
+            DeviceCommand::Recalibrate { .. } => Ok(DeviceReply::Recalibrated { correction: field("correction")?.as_i64().ok_or("`correction` is not an integer")? }),
+            DeviceCommand::SetInterval { .. } => Ok(DeviceReply::Interval { seconds: field("seconds")?.as_u64().ok_or("`seconds` is not an integer")? }),
+            DeviceCommand::FirmwareInfo => Ok(DeviceReply::Firmware {
+                version: field("version")?.as_str().ok_or("`version` is not a string")?.to_string(),
+                micropython: reply["micropython"].as_str().map(str::to_string),
+                mac: reply["mac"].as_str().map(str::to_string),
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceReply {
+    SelfTest { malfunction: bool },
+    Recalibrated { correction: i64 }, // ppm, the correction of the forced recalibration.
+    Interval { seconds: u64 }, // The interval which is applied.
+    Firmware { version: String, micropython: Option<String>, mac: Option<String> },
+    Error(String),
+}
+
+impl Display for DeviceReply {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            DeviceReply::SelfTest { malfunction: false } => write!(f, "Self-test passed"),
+            DeviceReply::SelfTest { malfunction: true } => write!(f, "Self-test failed: the SCD41 reports a malfunction"),
+            DeviceReply::Recalibrated { correction } => write!(f, "Recalibrated with the correction of {} ppm", correction),
+            DeviceReply::Interval { seconds } => write!(f, "Publish interval is {} s", seconds),
+            DeviceReply::Firmware { version, micropython, mac } => {
+                write!(f, "Firmware {}", version)?;
+                if let Some(micropython) = micropython {
+                    write!(f, ", MicroPython {}", micropython)?;
+                }
+                if let Some(mac) = mac {
+                    write!(f, ", MAC {}", mac)?;
+                }
+                Ok(())
+            },
+            DeviceReply::Error(error) => write!(f, "Error from the node: {}", error),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceRequest {
+    pub entity: String,
+    pub command: DeviceCommand,
+    pub timeout: Duration,
+}
+
+impl DeviceRequest {
+    pub fn request_topic(&self) -> String {
+        format!("i483/devices/{}/request/{}", self.entity, self.command.name())
+    }
+
+    /// The response topic is per client, so the replies to the other clients are not received.
+    pub fn response_topic(&self, client_id: &str) -> String {
+        format!("i483/devices/{}/response/{}", self.entity, client_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(DeviceCommand::parse("recalibrate", Some("420")), Some(DeviceCommand::Recalibrate { co2: 420 }));
+        assert_eq!(DeviceCommand::parse("interval", Some("30")).unwrap().payload(), json!({"command": "interval", "seconds": 30}));
+        assert_eq!(DeviceCommand::parse("self-test", None), Some(DeviceCommand::SelfTest));
+        assert_eq!(DeviceCommand::parse("interval", Some("0")), None);
+        assert_eq!(DeviceCommand::parse("firmware", Some("1")), None);
+        assert_eq!(DeviceCommand::parse("reboot", None), None);
+        let request = DeviceRequest { entity: "s2420010".to_string(), command: DeviceCommand::SelfTest, timeout: Duration::from_secs(20) };
+        assert_eq!(request.request_topic(), "i483/devices/s2420010/request/self-test");
+        assert_eq!(request.response_topic("cli"), "i483/devices/s2420010/response/cli");
+    }
+
+    #[test]
+    fn test_parse_reply() {
+        assert_eq!(DeviceCommand::SelfTest.parse_reply(br#"{"status": "ok", "malfunction": false}"#), Ok(DeviceReply::SelfTest { malfunction: false }));
+        assert_eq!(DeviceCommand::Recalibrate { co2: 420 }.parse_reply(br#"{"correction": -12}"#), Ok(DeviceReply::Recalibrated { correction: -12 }));
+        assert_eq!(DeviceCommand::FirmwareInfo.parse_reply(br#"{"version": "1.2.0", "mac": "24:0A:C4:00:00:01"}"#).unwrap().to_string(),
+            "Firmware 1.2.0, MAC 24:0A:C4:00:00:01");
+        assert_eq!(DeviceCommand::SetInterval { seconds: 30 }.parse_reply(br#"{"status": "error", "error": "out of range"}"#),
+            Ok(DeviceReply::Error("out of range".to_string())));
+        assert!(DeviceCommand::SetInterval { seconds: 30 }.parse_reply(br#"{"status": "ok"}"#).is_err());
+        assert!(DeviceCommand::SelfTest.parse_reply(b"ok").is_err());
+    }
+}
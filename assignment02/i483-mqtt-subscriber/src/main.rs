@@ -1,7 +1,11 @@
 mod cli;
+mod device;
 mod mqtt;
+mod output;
+mod simulate;
 
 use std::env;
+use std::process;
 use futures::executor::block_on;
 use rand::distributions::{Alphanumeric, DistString};
 
@@ -16,10 +20,66 @@ async fn main() {
             cli::print_usage();
         }
         cli::Command::Listen(args) => {
-            let client_id = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
-            let (client, mut event_loop) = block_on(mqtt::connect(&client_id, &args.host)).unwrap();
-            block_on(mqtt::subscribe(&client, &args.topics)).unwrap(); //&args.topics.iter().map(|s| s.as_str()).collect())
-            block_on(mqtt::listen(&mut event_loop)).unwrap();
+            let client_id = args.client_id.clone().unwrap_or_else(|| Alphanumeric.sample_string(&mut rand::thread_rng(), 16));
+            eprintln!("Client id: {}", &client_id);
+            let (client, mut event_loop) = block_on(mqtt::connect(&client_id, &args)).unwrap();
+            block_on(mqtt::listen(&client, &mut event_loop, &args)).unwrap();
+        }
+        cli::Command::Publish(args, publication) => {
+            let client_id = args.client_id.clone().unwrap_or_else(|| Alphanumeric.sample_string(&mut rand::thread_rng(), 16));
+            let payload = match mqtt::read_payload(&publication.input) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    eprintln!("Error: cannot read the input: {}", e);
+                    process::exit(1);
+                }
+            };
+            // The messages are sent by a spawned task, so the event loop is awaited on the runtime.
+            let (client, mut event_loop) = mqtt::connect(&client_id, &args).await.unwrap();
+            match mqtt::publish(&client, &mut event_loop, &publication, payload).await {
+                Ok(count) => eprintln!("Published {} messages to {}", count, publication.topic),
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    process::exit(1);
+                }
+            }
+        }
+        cli::Command::Simulate(args, simulation) => {
+            let client_id = args.client_id.clone().unwrap_or_else(|| Alphanumeric.sample_string(&mut rand::thread_rng(), 16));
+            let (client, mut event_loop) = mqtt::connect(&client_id, &args).await.unwrap();
+            match mqtt::simulate(&client, &mut event_loop, &simulation).await {
+                Ok(count) => eprintln!("Published {} messages of {} entities", count, simulation.entities.len()),
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    process::exit(1);
+                }
+            }
+        }
+        cli::Command::Device(args, request) => {
+            let client_id = args.client_id.clone().unwrap_or_else(|| Alphanumeric.sample_string(&mut rand::thread_rng(), 16));
+            let (client, mut event_loop) = mqtt::connect(&client_id, &args).await.unwrap();
+            let payload = match mqtt::request(&client, &mut event_loop, &request, &client_id).await {
+                Ok(payload) => payload,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    process::exit(1);
+                }
+            };
+            match request.command.parse_reply(&payload) {
+                Ok(reply) => {
+                    match args.format {
+                        output::OutputFormat::JsonLines => println!("{}", String::from_utf8_lossy(&payload).trim()),
+                        _ => println!("{}", reply),
+                    }
+                    if matches!(reply, device::DeviceReply::Error(_)) {
+                        process::exit(1);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    process::exit(1);
+                }
+            }
         }
     }
 }
@@ -1,32 +1,466 @@
-use std::time::Duration;
+use std::fmt;
+use std::fs;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use bytes::Bytes;
+use chrono::Utc;
+use rand::Rng;
+use rand::distributions::{Alphanumeric, DistString};
+use rumqttc::{Outgoing, TlsConfiguration, Transport};
 use rumqttc::v5::{AsyncClient, Event, EventLoop, MqttOptions};
 use rumqttc::v5::mqttbytes::{QoS};
+use rumqttc::v5::mqttbytes::v5::{ConnectProperties, Filter, Packet, PublishProperties, RetainForwardRule, SubscribeProperties};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::task::JoinSet;
+use crate::cli::{Args, Input, Publication, Scheme};
+use crate::device::DeviceRequest;
+use crate::output::{Message, OutputFormat, CSV_HEADER};
+use crate::simulate::{Node, Simulation};
 
-pub async fn connect(client_id: &str, host: &str) -> Result<(AsyncClient, EventLoop), rumqttc::ConnectionError> {
-    let mut mqtt_options = MqttOptions::new(client_id, host, 1883);
-    mqtt_options.set_keep_alive(Duration::from_secs(30));
+// Same as connect_wifi of mqtt_pub.py, the interval is doubled up to the maximum.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(120);
+// The gap which ends the delivery of the queued messages after the session is resumed.
+const QUEUE_QUIET: Duration = Duration::from_secs(1);
+// The queued messages are counted for this time at most, since the live messages never leave the gap under continuous traffic.
+const QUEUE_DEADLINE: Duration = Duration::from_secs(10);
+
+// Without the CA file, the server is verified with the system roots.
+fn tls_configuration(args: &Args) -> Result<TlsConfiguration, std::io::Error> {
+    let ca = match &args.ca_file {
+        Some(ca_file) => fs::read(ca_file)?,
+        None => return Ok(TlsConfiguration::default()),
+    };
+    let client_auth = match (&args.client_cert, &args.client_key) {
+        (Some(cert), Some(key)) => Some((fs::read(cert)?, fs::read(key)?)),
+        _ => None,
+    };
+    Ok(TlsConfiguration::Simple { ca, alpn: None, client_auth })
+}
+
+pub async fn connect(client_id: &str, args: &Args) -> Result<(AsyncClient, EventLoop), rumqttc::ConnectionError> {
+    let broker = &args.broker;
+    let mut mqtt_options = match broker.scheme {
+        Scheme::Ws | Scheme::Wss => MqttOptions::new(client_id, broker.url(), broker.port),
+        Scheme::Mqtt | Scheme::Mqtts => MqttOptions::new(client_id, &broker.host, broker.port),
+    };
+    mqtt_options.set_keep_alive(Duration::from_secs(args.keep_alive));
+    // The broker keeps the session (the subscriptions and the QoS 1/2 messages) for the expiry after a disconnect.
+    if args.session_expiry > 0 {
+        let mut properties = ConnectProperties::new();
+        properties.session_expiry_interval = Some(args.session_expiry);
+        mqtt_options.set_clean_start(false);
+        mqtt_options.set_connect_properties(properties);
+    }
+    if let Some(username) = &broker.username {
+        mqtt_options.set_credentials(username, broker.password.clone().unwrap_or_default());
+    }
+    let transport = match broker.scheme {
+        Scheme::Mqtt => Transport::Tcp,
+        Scheme::Mqtts => Transport::Tls(tls_configuration(args)?),
+        Scheme::Ws => Transport::Ws,
+        Scheme::Wss => Transport::Wss(tls_configuration(args)?),
+    };
+    mqtt_options.set_transport(transport);
     let (client, event_loop) = AsyncClient::new(mqtt_options, 100);
     Ok((client, event_loop))
 }
 
-pub async fn subscribe(client: &AsyncClient, topics: &Vec<String>) -> Result<(), rumqttc::v5::ClientError> {
-    for topic in topics {
-        client.subscribe(topic.clone(), QoS::AtMostOnce).await?;
+fn qos(qos: u8) -> QoS {
+    match qos {
+        2 => QoS::ExactlyOnce,
+        1 => QoS::AtLeastOnce,
+        _ => QoS::AtMostOnce,
+    }
+}
+
+// A subscription identifier is a property of the SUBSCRIBE packet, so every topic is subscribed in its own packet.
+/// The requests are queued without waiting, since they are called from the event loop which sends them.
+/// Awaiting the bounded request channel there would deadlock once it is full.
+pub fn subscribe(client: &AsyncClient, args: &Args) -> Result<(), Box<rumqttc::v5::ClientError>> {
+    for subscription in &args.subscriptions {
+        let mut filter = Filter::new(subscription.topic.clone(), qos(subscription.qos));
+        filter.nolocal = args.no_local;
+        filter.preserve_retain = args.retain_as_published;
+        filter.retain_forward_rule = match args.retain_handling {
+            1 => RetainForwardRule::OnNewSubscribe,
+            2 => RetainForwardRule::Never,
+            _ => RetainForwardRule::OnEverySubscribe,
+        };
+        match subscription.id {
+            Some(id) => client.try_subscribe_many_with_properties([filter], SubscribeProperties { id: Some(id), user_properties: Vec::new() }).map_err(Box::new)?,
+            None => client.try_subscribe_many([filter]).map_err(Box::new)?,
+        }
     }
     Ok(())
 }
 
-pub async fn listen(event_loop: &mut EventLoop) -> Result<(), rumqttc::ConnectionError> {
+/// Exponential backoff with jitter, so the subscribers do not reconnect at once after the broker restarts.
+pub struct Backoff {
+    interval: Duration,
+}
+
+impl Backoff {
+    pub fn new() -> Backoff {
+        Backoff { interval: INITIAL_BACKOFF }
+    }
+
+    /// The delay before the next attempt: a random delay between the half and the whole of the interval.
+    pub fn next_delay(&mut self) -> Duration {
+        let half = self.interval / 2;
+        let delay = half + half.mul_f64(rand::thread_rng().gen_range(0.0..=1.0));
+        self.interval = (self.interval * 2).min(MAX_BACKOFF);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.interval = INITIAL_BACKOFF;
+    }
+}
+
+/// Counts the queued messages, which the broker delivers at once after the session is resumed.
+struct QueuedMessages {
+    count: usize,
+    resumed: Instant,
+    last: Instant,
+}
+
+impl QueuedMessages {
+    fn new(now: Instant) -> QueuedMessages {
+        QueuedMessages { count: 0, resumed: now, last: now }
+    }
+
+    /// Counts the QoS 1/2 publish of the burst. Returns false when the burst is over, after the gap or the deadline.
+    fn record(&mut self, now: Instant, is_queued_publish: bool) -> bool {
+        if now.duration_since(self.last) > QUEUE_QUIET || now.duration_since(self.resumed) > QUEUE_DEADLINE {
+            return false;
+        }
+        if is_queued_publish {
+            self.count += 1;
+            self.last = now;
+        }
+        true
+    }
+}
+
+// The event loop reconnects on the next poll after an error. The subscriptions are restored on every
+// connection, unless the broker resumed the session which keeps them.
+// Only the incoming publishes are printed to stdout (the other events and the states are to stderr), so the output can be piped.
+pub async fn listen(client: &AsyncClient, event_loop: &mut EventLoop, args: &Args) -> Result<(), rumqttc::ConnectionError> {
+    let mut backoff = Backoff::new();
+    if args.format == OutputFormat::Csv {
+        println!("{}", CSV_HEADER);
+    }
+    eprintln!("State: connecting");
+    let mut queued: Option<QueuedMessages> = None;
     loop {
         let event = event_loop.poll().await;
+        // The burst is over at the first event after the gap or the deadline, or on the first ping response,
+        // which the broker sends after the queued messages.
+        if let Some(messages) = &mut queued {
+            let is_queued_publish = matches!(&event, Ok(Event::Incoming(Packet::Publish(publish))) if publish.qos != QoS::AtMostOnce);
+            let is_ping_response = matches!(&event, Ok(Event::Incoming(Packet::PingResp(_))));
+            if event.is_err() || is_ping_response || !messages.record(Instant::now(), is_queued_publish) {
+                eprintln!("Received {} queued messages on resume", messages.count);
+                queued = None;
+            }
+        }
         match &event {
-            Ok(v) => {
-                println!("Event = {v:?}");
+            Ok(Event::Incoming(Packet::ConnAck(connack))) => {
+                eprintln!("State: connected");
+                backoff.reset();
+                if connack.session_present {
+                    eprintln!("Session is resumed, keeping the subscriptions");
+                    queued = Some(QueuedMessages::new(Instant::now()));
+                } else if let Err(e) = subscribe(client, args) {
+                    eprintln!("Error = {e:?}");
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                let mut message = Message::new(&String::from_utf8_lossy(&publish.topic), publish.qos as u8, publish.retain, &publish.payload);
+                if let Some(properties) = &publish.properties {
+                    message.subscriptions = properties.subscription_identifiers.iter()
+                        .filter_map(|id| args.subscriptions.iter().find(|subscription| subscription.id == Some(*id)))
+                        .map(|subscription| subscription.topic.clone())
+                        .collect();
+                }
+                println!("{}", message.format(&args.format));
             }
+            Ok(_) => {}
             Err(e) => {
-                println!("Error = {e:?}");
-                return Ok(());
+                let delay = backoff.next_delay();
+                eprintln!("State: disconnected ({e}), reconnecting in {:.1} s", delay.as_secs_f64());
+                tokio::time::sleep(delay).await;
+                eprintln!("State: reconnecting");
+            }
+        }
+    }
+}
+
+/// The error which stops the publish before all the messages are sent.
+#[derive(Debug)]
+pub enum PublishError {
+    Input(std::io::Error),
+    Client(Box<rumqttc::v5::ClientError>), // The error holds the whole request.
+}
+
+impl fmt::Display for PublishError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PublishError::Input(e) => write!(f, "cannot read the input: {}", e),
+            PublishError::Client(e) => write!(f, "cannot send the message: {}", e),
+        }
+    }
+}
+
+/// Reads the payload of the value or the file before connecting, so a missing file fails at once.
+/// None for stdin, whose lines are read while publishing.
+pub fn read_payload(input: &Input) -> Result<Option<Vec<u8>>, std::io::Error> {
+    match input {
+        Input::Value(value) => Ok(Some(value.clone().into_bytes())),
+        Input::File(path) => fs::read(path).map(Some),
+        Input::Stdin => Ok(None),
+    }
+}
+
+async fn send(client: &AsyncClient, publication: &Publication, payload: Vec<u8>) -> Result<(), PublishError> {
+    let properties = PublishProperties {
+        message_expiry_interval: publication.message_expiry,
+        content_type: publication.content_type.clone(),
+        user_properties: publication.user_properties.clone(),
+        response_topic: publication.response_topic.clone(),
+        ..Default::default()
+    };
+    client.publish_with_properties(publication.topic.clone(), qos(publication.qos), publication.retain, payload, properties).await
+        .map_err(|e| PublishError::Client(Box::new(e)))
+}
+
+// Returns the number of the messages which are handed to the event loop.
+async fn send_messages(client: AsyncClient, publication: Publication, payload: Option<Vec<u8>>) -> Result<u64, PublishError> {
+    let interval = publication.repeat.map(Duration::from_secs);
+    let mut sent = 0;
+    let payload = match payload {
+        Some(payload) => payload,
+        None => {
+            let mut lines = BufReader::new(tokio::io::stdin()).lines();
+            while publication.count.is_none_or(|count| sent < count) {
+                let line = match lines.next_line().await.map_err(PublishError::Input)? {
+                    Some(line) => line,
+                    None => break,
+                };
+                send(&client, &publication, line.into_bytes()).await?;
+                sent += 1;
+                if let Some(interval) = interval {
+                    tokio::time::sleep(interval).await;
+                }
+            }
+            return Ok(sent);
+        },
+    };
+    // Without --repeat the message is sent once.
+    let count = if interval.is_some() { publication.count } else { Some(publication.count.unwrap_or(1).min(1)) };
+    while count.is_none_or(|count| sent < count) {
+        if sent > 0 {
+            tokio::time::sleep(interval.unwrap_or_default()).await;
+        }
+        send(&client, &publication, payload.clone()).await?;
+        sent += 1;
+    }
+    Ok(sent)
+}
+
+/// Publishes the messages, and disconnects after the broker acknowledged all of them. The messages of QoS 0 are
+/// not acknowledged, they are flushed by the disconnect which is queued after them. Returns the number of the messages.
+pub async fn publish(client: &AsyncClient, event_loop: &mut EventLoop, publication: &Publication, payload: Option<Vec<u8>>) -> Result<u64, PublishError> {
+    let mut sender = tokio::spawn(send_messages(client.clone(), publication.clone(), payload));
+    let mut backoff = Backoff::new();
+    let (mut acknowledged, mut total) = (0, None);
+    eprintln!("State: connecting");
+    while !total.is_some_and(|total| publication.qos == 0 || acknowledged >= total) {
+        tokio::select! {
+            result = &mut sender, if total.is_none() => total = Some(result.expect("the sender panicked")?),
+            event = event_loop.poll() => {
+                if matches!(event, Ok(Event::Incoming(Packet::PubAck(_) | Packet::PubComp(_)))) {
+                    acknowledged += 1;
+                }
+                track_connection(&event, &mut backoff).await;
+            }
+        }
+    }
+    disconnect(client, event_loop).await;
+    Ok(total.unwrap_or(0))
+}
+
+// Reports the state of the connection, and waits for the backoff after an error. The event loop reconnects on the next poll.
+async fn track_connection(event: &Result<Event, rumqttc::v5::ConnectionError>, backoff: &mut Backoff) {
+    match event {
+        Ok(Event::Incoming(Packet::ConnAck(_))) => {
+            eprintln!("State: connected");
+            backoff.reset();
+        }
+        Ok(_) => {}
+        Err(e) => {
+            let delay = backoff.next_delay();
+            eprintln!("State: disconnected ({e}), reconnecting in {:.1} s", delay.as_secs_f64());
+            tokio::time::sleep(delay).await;
+            eprintln!("State: reconnecting");
+        }
+    }
+}
+
+// The requests which are queued before the disconnect are sent before it.
+async fn disconnect(client: &AsyncClient, event_loop: &mut EventLoop) {
+    if client.disconnect().await.is_ok() {
+        while !matches!(event_loop.poll().await, Ok(Event::Outgoing(Outgoing::Disconnect)) | Err(_)) {}
+    }
+}
+
+// Publishes the cycles of a virtual node. The faults are reported to stderr, so they can be matched with the alerts.
+async fn run_node(client: AsyncClient, simulation: Arc<Simulation>, mut node: Node) -> Result<u64, PublishError> {
+    // The nodes are spread over the interval, so they do not publish at once.
+    let offset = simulation.interval.mul_f64(rand::thread_rng().gen_range(0.0..1.0));
+    tokio::time::sleep(offset).await;
+    let mut ticker = tokio::time::interval(simulation.interval);
+    let (mut cycles, mut sent) = (0, 0);
+    while simulation.count.is_none_or(|count| cycles < count) {
+        ticker.tick().await;
+        cycles += 1;
+        let mut faults = Vec::new();
+        let readings = node.read(&simulation, simulation.phase(Utc::now()), &mut faults);
+        for fault in faults {
+            eprintln!("[{}] {}", node.entity, fault);
+        }
+        for (topic, payload) in readings.map(|readings| readings.messages(&node.entity, &simulation.document)).unwrap_or_default() {
+            client.publish(topic, qos(simulation.qos), false, payload).await
+                .map_err(|e| PublishError::Client(Box::new(e)))?;
+            sent += 1;
+        }
+    }
+    Ok(sent)
+}
+
+/// Runs the virtual nodes until every node has published its cycles (forever without the count). Returns the number of the messages.
+pub async fn simulate(client: &AsyncClient, event_loop: &mut EventLoop, simulation: &Simulation) -> Result<u64, PublishError> {
+    let mut nodes = JoinSet::new();
+    let shared = Arc::new(simulation.clone());
+    for node in simulation.nodes() {
+        nodes.spawn(run_node(client.clone(), shared.clone(), node));
+    }
+    let mut backoff = Backoff::new();
+    let mut sent = 0;
+    eprintln!("State: connecting");
+    while !nodes.is_empty() {
+        tokio::select! {
+            Some(result) = nodes.join_next() => sent += result.expect("the node panicked")?,
+            event = event_loop.poll() => track_connection(&event, &mut backoff).await,
+        }
+    }
+    disconnect(client, event_loop).await;
+    Ok(sent)
+}
+
+/// The error which ends the request without a reply.
+#[derive(Debug)]
+pub enum RequestError {
+    Timeout(Duration),
+    Client(Box<rumqttc::v5::ClientError>), // The error holds the whole request.
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RequestError::Timeout(timeout) => write!(f, "no reply within {:.1} s", timeout.as_secs_f64()),
+            RequestError::Client(e) => write!(f, "cannot send the request: {}", e),
+        }
+    }
+}
+
+impl From<rumqttc::v5::ClientError> for RequestError {
+    fn from(e: rumqttc::v5::ClientError) -> RequestError {
+        RequestError::Client(Box::new(e))
+    }
+}
+
+/// Sends the request to the node, and waits for the reply with the same correlation data. Returns the payload of the reply.
+/// The timeout covers the connection too, and the request expires on the broker after it, so a late node does not run it.
+pub async fn request(client: &AsyncClient, event_loop: &mut EventLoop, request: &DeviceRequest, client_id: &str) -> Result<Vec<u8>, RequestError> {
+    let response_topic = request.response_topic(client_id);
+    let correlation_data = Bytes::from(Alphanumeric.sample_string(&mut rand::thread_rng(), 16));
+    let properties = PublishProperties {
+        message_expiry_interval: Some(request.timeout.as_secs_f64().ceil() as u32),
+        content_type: Some("application/json".to_string()),
+        response_topic: Some(response_topic.clone()),
+        correlation_data: Some(correlation_data.clone()),
+        ..Default::default()
+    };
+    let mut backoff = Backoff::new();
+    let mut sent = false;
+    eprintln!("State: connecting");
+    let reply = tokio::time::timeout(request.timeout, async {
+        loop {
+            let event = event_loop.poll().await;
+            // The requests are queued without waiting, since this loop is the event loop which sends them.
+            // The request is sent after the response topic is subscribed, so the reply is never missed.
+            match &event {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => client.try_subscribe(response_topic.clone(), QoS::AtLeastOnce)?,
+                Ok(Event::Incoming(Packet::SubAck(_))) if !sent => {
+                    eprintln!("Sending {} to {}", request.command.name(), request.entity);
+                    let payload = request.command.payload().to_string();
+                    client.try_publish_with_properties(request.request_topic(), QoS::AtLeastOnce, false, payload, properties.clone())?;
+                    sent = true;
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    let correlation = publish.properties.as_ref().and_then(|properties| properties.correlation_data.as_ref());
+                    if correlation == Some(&correlation_data) {
+                        return Ok(publish.payload.to_vec());
+                    }
+                }
+                _ => {}
             }
+            track_connection(&event, &mut backoff).await;
+        }
+    }).await;
+    disconnect(client, event_loop).await;
+    reply.unwrap_or(Err(RequestError::Timeout(request.timeout)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new();
+        let mut previous_max = Duration::ZERO;
+        for _ in 0..10 {
+            let max = backoff.interval;
+            let delay = backoff.next_delay();
+            assert!(delay >= max / 2 && delay <= max, "{:?} is out of {:?}", delay, max);
+            assert!(max >= previous_max && max <= MAX_BACKOFF);
+            previous_max = max;
+        }
+        assert_eq!(backoff.interval, MAX_BACKOFF);
+        backoff.reset();
+        assert!(backoff.next_delay() <= INITIAL_BACKOFF);
+    }
+
+    #[test]
+    fn test_count_queued_messages() {
+        let resumed = Instant::now();
+        let mut queued = QueuedMessages::new(resumed);
+        for i in 1..=5 {
+            assert!(queued.record(resumed + Duration::from_millis(i * 100), true));
+            assert!(queued.record(resumed + Duration::from_millis(i * 100 + 10), false));
+        }
+        assert!(queued.record(resumed + Duration::from_millis(900), false));
+        assert!(!queued.record(resumed + Duration::from_millis(1600), true));
+        assert_eq!(queued.count, 5);
+        // Continuous traffic ends at the deadline.
+        let mut queued = QueuedMessages::new(resumed);
+        for i in 1..=100 {
+            assert!(queued.record(resumed + Duration::from_millis(i * 100), true));
         }
+        assert!(!queued.record(resumed + Duration::from_millis(10100), true));
+        assert_eq!(queued.count, 100);
     }
 }
@@ -0,0 +1,196 @@
+/*
+    This is the output module. It decodes the payload of a publish and prints it.
+    * Payload: a plain number, a UTF-8 string, or the JSON / MessagePack document of mqtt_pub.py
+      (decoded by the topic suffix `/json` and `/msgpack`, or by the content).
+    * Format: human, JSON lines or CSV, with the topic, the QoS, the retain flag and the receive time.
+*/
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{json, Value};
+use i483_sensors::SensorTopic;
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputFormat {
+    Human,
+    JsonLines,
+    Csv,
+}
+
+impl OutputFormat {
+    pub fn parse(format: &str) -> Option<OutputFormat> {
+        match format.to_ascii_lowercase().as_str() {
+            "human" => Some(OutputFormat::Human),
+            "json" | "jsonl" | "json-lines" => Some(OutputFormat::JsonLines),
+            "csv" => Some(OutputFormat::Csv),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Payload {
+    Number(f64),
+    Text(String),
+    Document(Value), // JSON or MessagePack
+    Binary(Vec<u8>),
+}
+
+impl Payload {
+    pub fn decode(topic: &str, payload: &[u8]) -> Payload {
+        if topic.ends_with("/msgpack") {
+            if let Ok(document) = rmp_serde::from_slice::<Value>(payload) {
+                return Payload::Document(document);
+            }
+        }
+        match std::str::from_utf8(payload) {
+            Ok(text) => {
+                let text = text.trim();
+                if let Ok(number) = text.parse::<f64>() {
+                    return Payload::Number(number);
+                }
+                match serde_json::from_str::<Value>(text) {
+                    Ok(document) if document.is_object() || document.is_array() => Payload::Document(document),
+                    _ => Payload::Text(text.to_string()),
+                }
+            },
+            // The MessagePack document is not UTF-8 in most cases.
+            Err(_) => match rmp_serde::from_slice::<Value>(payload) {
+                Ok(document) if document.is_object() || document.is_array() => Payload::Document(document),
+                _ => Payload::Binary(payload.to_vec()),
+            },
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        match self {
+            Payload::Number(number) => json!(number),
+            Payload::Text(text) => json!(text),
+            Payload::Document(document) => document.clone(),
+            Payload::Binary(bytes) => json!(hex(bytes)),
+        }
+    }
+
+    // The value in a single line, e.g. for the CSV cell.
+    fn to_plain(&self) -> String {
+        match self {
+            Payload::Number(number) => number.to_string(),
+            Payload::Text(text) => text.clone(),
+            Payload::Document(document) => document.to_string(),
+            Payload::Binary(bytes) => hex(bytes),
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// An incoming publish, which is printed in the output format.
+pub struct Message {
+    pub topic: String,
+    pub qos: u8,
+    pub retain: bool,
+    pub received_at: DateTime<Utc>,
+    pub payload: Payload,
+    pub subscriptions: Vec<String>, // The filters which matched, by the subscription identifiers.
+}
+
+impl Message {
+    pub fn new(topic: &str, qos: u8, retain: bool, payload: &[u8]) -> Message {
+        Message {
+            topic: topic.to_string(),
+            qos,
+            retain,
+            received_at: Utc::now(),
+            payload: Payload::decode(topic, payload),
+            subscriptions: Vec::new(),
+        }
+    }
+
+    fn unit(&self) -> Option<&'static str> {
+        SensorTopic::parse(&self.topic).and_then(|topic| topic.unit())
+    }
+
+    pub fn format(&self, format: &OutputFormat) -> String {
+        let received_at = self.received_at.to_rfc3339_opts(SecondsFormat::Millis, true);
+        match format {
+            OutputFormat::Human => {
+                let retain = if self.retain { " retained" } else { "" };
+                let value = match SensorTopic::parse(&self.topic) {
+                    Some(topic) => format!("[{}] {} {} = {} {}", topic.entity, topic.sensor, topic.data_type, self.payload.to_plain(), topic.unit().unwrap_or("")),
+                    None => format!("{} = {}", self.topic, self.payload.to_plain()),
+                };
+                let via = if self.subscriptions.is_empty() { String::new() } else { format!(" via {}", self.subscriptions.join(", ")) };
+                format!("{} (QoS {}{}) {}{}", received_at, self.qos, retain, value.trim_end(), via)
+            },
+            OutputFormat::JsonLines => {
+                let mut line = json!({
+                    "received_at": received_at,
+                    "topic": self.topic,
+                    "qos": self.qos,
+                    "retain": self.retain,
+                    "value": self.payload.to_json(),
+                });
+                if let Some(topic) = SensorTopic::parse(&self.topic) {
+                    line["entity"] = json!(topic.entity);
+                    line["sensor"] = json!(topic.sensor.to_string());
+                    line["data_type"] = json!(topic.data_type.to_string());
+                    line["unit"] = json!(topic.unit());
+                }
+                if !self.subscriptions.is_empty() {
+                    line["subscriptions"] = json!(self.subscriptions);
+                }
+                line.to_string()
+            },
+            OutputFormat::Csv => [
+                received_at,
+                csv_field(&self.topic),
+                self.qos.to_string(),
+                self.retain.to_string(),
+                csv_field(&self.payload.to_plain()),
+                self.unit().unwrap_or("").to_string(),
+            ].join(","),
+        }
+    }
+}
+
+pub const CSV_HEADER: &str = "received_at,topic,qos,retain,value,unit";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_payload() {
+        assert_eq!(Payload::decode("i483/sensors/s2420010/SCD41/co2", b"812"), Payload::Number(812.0));
+        assert_eq!(Payload::decode("i483/status", b"online"), Payload::Text("online".to_string()));
+        let document = json!({"bmp180": {"temperature": 24.5, "air_pressure": 1013.25}});
+        assert_eq!(Payload::decode("i483/sensors/s2420010/json", document.to_string().as_bytes()), Payload::Document(document.clone()));
+        let packed = rmp_serde::to_vec(&document).unwrap();
+        assert_eq!(Payload::decode("i483/sensors/s2420010/msgpack", &packed), Payload::Document(document));
+        assert_eq!(Payload::decode("i483/raw", &[0xff, 0x00]), Payload::Binary(vec![0xff, 0x00]));
+    }
+
+    #[test]
+    fn test_format_message() {
+        let mut message = Message::new("i483/sensors/s2420010/SCD41/co2", 1, true, b"812");
+        message.received_at = DateTime::parse_from_rfc3339("2026-10-18T09:00:00Z").unwrap().with_timezone(&Utc);
+        assert_eq!(message.format(&OutputFormat::Human), "2026-10-18T09:00:00.000Z (QoS 1 retained) [s2420010] SCD41 co2 = 812 ppm");
+        assert_eq!(message.format(&OutputFormat::Csv), "2026-10-18T09:00:00.000Z,i483/sensors/s2420010/SCD41/co2,1,true,812,ppm");
+        let line: Value = serde_json::from_str(&message.format(&OutputFormat::JsonLines)).unwrap();
+        assert_eq!(line["value"], json!(812.0));
+        assert_eq!(line["entity"], "s2420010");
+        message.subscriptions = vec!["i483/sensors/+/SCD41/co2".to_string()];
+        assert!(message.format(&OutputFormat::Human).ends_with("812 ppm via i483/sensors/+/SCD41/co2"));
+        message.payload = Payload::Document(json!({"co2": 812}));
+        assert_eq!(message.format(&OutputFormat::Csv), "2026-10-18T09:00:00.000Z,i483/sensors/s2420010/SCD41/co2,1,true,\"{\"\"co2\"\":812}\",ppm");
+    }
+}
@@ -0,0 +1,369 @@
+/*
+    This is the simulate module. It emulates the ESP32 node of mqtt_pub.py for the load tests and the demos without the hardware.
+    * Messages: every interval, the JSON or MessagePack document and the per-metric strings of an entity,
+      on the same topics and in the same order as publish_sensor_data.
+    * Readings: the daily cycle of every data type (a sine which peaks at 15:00 of the simulated day) with the gaussian noise
+      and a small offset per entity. The SCD41 reads the temperature a little higher than the BMP180 (self-heating).
+    * Faults, by the probability per cycle: dropout (the node is silent for 1 to 8 cycles), spike (a reading jumps by
+      several amplitudes), stuck (a reading repeats for 20 cycles), and missing SCD41 humidity (null in the document,
+      and the SCD41 topics are skipped as mqtt_pub.py does).
+*/
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde_json::{json, Value};
+use i483_sensors::{DataType, Sensor, SensorTopic};
+
+const STUCK_CYCLES: u64 = 20;
+const MAX_DROPOUT_CYCLES: u64 = 8;
+const SCD41_TEMPERATURE_OFFSET: f64 = 0.8;
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Document {
+    Json,
+    MessagePack,
+}
+
+impl Document {
+    pub fn parse(document: &str) -> Option<Document> {
+        match document.to_ascii_lowercase().as_str() {
+            "json" => Some(Document::Json),
+            "msgpack" | "messagepack" => Some(Document::MessagePack),
+            _ => None,
+        }
+    }
+}
+
+/// The daily cycle of a data type: mean + amplitude * sin, with the gaussian noise of the standard deviation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cycle {
+    pub data_type: DataType,
+    pub mean: f64,
+    pub amplitude: f64, // A negative amplitude peaks at night, e.g. the humidity.
+    pub noise: f64,
+}
+
+impl Cycle {
+    /// Parses `<data type>:<mean>:<amplitude>[:<noise>]`, e.g. `co2:800:300:20`. Only the data types of the node are simulated.
+    pub fn parse(cycle: &str) -> Option<Cycle> {
+        let parts: Vec<&str> = cycle.split(':').collect();
+        if parts.len() < 3 || parts.len() > 4 {
+            return None;
+        }
+        let data_type = DataType::parse(parts[0]);
+        let default = Cycle::defaults().into_iter().find(|cycle| cycle.data_type == data_type)?;
+        Some(Cycle {
+            data_type,
+            mean: parts[1].parse().ok()?,
+            amplitude: parts[2].parse().ok()?,
+            noise: match parts.get(3) {
+                Some(noise) => noise.parse().ok()?,
+                None => default.noise,
+            },
+        })
+    }
+
+    /// An indoor room: the air pressure in Pa as the BMP180 reports it.
+    pub fn defaults() -> Vec<Cycle> {
+        vec![
+            Cycle { data_type: DataType::Temperature, mean: 24.0, amplitude: 3.0, noise: 0.1 },
+            Cycle { data_type: DataType::Humidity, mean: 45.0, amplitude: -8.0, noise: 0.5 },
+            Cycle { data_type: DataType::Co2, mean: 700.0, amplitude: 250.0, noise: 10.0 },
+            Cycle { data_type: DataType::AirPressure, mean: 101325.0, amplitude: 120.0, noise: 5.0 },
+        ]
+    }
+
+    // The phase is the time of the day from 0 to 1.
+    fn value(&self, phase: f64, noise_scale: f64, rng: &mut StdRng) -> f64 {
+        self.mean + self.amplitude * (2.0 * PI * (phase - 0.375)).sin() + self.noise * noise_scale * gaussian(rng)
+    }
+}
+
+// Box-Muller transform.
+fn gaussian(rng: &mut StdRng) -> f64 {
+    let (u1, u2): (f64, f64) = (rng.gen_range(f64::EPSILON..1.0), rng.gen_range(0.0..1.0));
+    (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()
+}
+
+/// The probabilities of the faults per cycle.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Faults {
+    pub dropout: f64,
+    pub spike: f64,
+    pub stuck: f64,
+    pub missing_humidity: f64,
+}
+
+impl Faults {
+    /// Sets a fault by `<fault>:<probability>`, e.g. `spike:0.01`. Returns false when it is invalid.
+    pub fn set(&mut self, fault: &str) -> bool {
+        let (kind, probability) = match fault.split_once(':').map(|(kind, probability)| (kind, probability.parse::<f64>())) {
+            Some((kind, Ok(probability))) if (0.0..=1.0).contains(&probability) => (kind, probability),
+            _ => return false,
+        };
+        match kind.to_ascii_lowercase().as_str() {
+            "dropout" => self.dropout = probability,
+            "spike" => self.spike = probability,
+            "stuck" => self.stuck = probability,
+            "missing-humidity" => self.missing_humidity = probability,
+            _ => return false,
+        }
+        true
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Simulation {
+    pub entities: Vec<String>,
+    pub interval: Duration,
+    pub day: u64, // seconds of a simulated day, shorter for the demos.
+    pub document: Document,
+    pub qos: u8,
+    pub count: Option<u64>, // The number of the cycles of every entity, unlimited if None.
+    pub cycles: Vec<Cycle>,
+    pub noise: f64, // The scale of the noise of every cycle.
+    pub faults: Faults,
+    pub seed: Option<u64>, // The same seed repeats the same readings and faults.
+}
+
+impl Simulation {
+    /// The time of the simulated day from 0 to 1.
+    pub fn phase(&self, time: DateTime<Utc>) -> f64 {
+        let day = self.day.max(1) as f64;
+        (time.timestamp_millis() as f64 / 1000.0).rem_euclid(day) / day
+    }
+
+    fn cycle(&self, data_type: &DataType) -> &Cycle {
+        self.cycles.iter().find(|cycle| &cycle.data_type == data_type)
+            .expect("every data type of the node has a cycle")
+    }
+
+    pub fn nodes(&self) -> Vec<Node> {
+        let seed = self.seed.unwrap_or_else(|| rand::thread_rng().gen_range(0..u64::MAX));
+        self.entities.iter().enumerate()
+            .map(|(index, entity)| Node::new(entity, seed.wrapping_add(index as u64), self))
+            .collect()
+    }
+}
+
+/// The readings of a cycle, the same data as publish_sensor_data.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Readings {
+    pub bmp180_temperature: f64,
+    pub bmp180_air_pressure: f64,
+    pub scd41_humidity: Option<f64>, // None when the SCD41 has no new measurement.
+    pub scd41_co2: f64,
+    pub scd41_temperature: f64,
+}
+
+// The values which mqtt_pub.py formats with f"{data}".
+fn text(data_type: &DataType, value: f64) -> String {
+    match data_type {
+        DataType::Co2 => format!("{}", value.round() as i64),
+        _ => format!("{}", (value * 100.0).round() / 100.0),
+    }
+}
+
+impl Readings {
+    fn document(&self) -> Value {
+        json!({
+            "bmp180": {
+                "temperature": self.bmp180_temperature,
+                "air_pressure": self.bmp180_air_pressure,
+            },
+            "scd41": {
+                "humidity": self.scd41_humidity,
+                "co2": self.scd41_co2.round() as i64,
+                "temperature": self.scd41_temperature,
+            },
+        })
+    }
+
+    /// The topics and the payloads of the cycle, in the order of publish_sensor_data.
+    pub fn messages(&self, entity: &str, document: &Document) -> Vec<(String, Vec<u8>)> {
+        let mut messages = match document {
+            Document::Json => vec![(format!("i483/sensors/{}/json", entity), self.document().to_string().into_bytes())],
+            Document::MessagePack => vec![(format!("i483/sensors/{}/msgpack", entity), rmp_serde::to_vec(&self.document()).unwrap())],
+        };
+        let mut metrics = vec![
+            (Sensor::Bmp180, DataType::Temperature, self.bmp180_temperature),
+            (Sensor::Bmp180, DataType::AirPressure, self.bmp180_air_pressure),
+        ];
+        if let Some(humidity) = self.scd41_humidity {
+            metrics.push((Sensor::Scd41, DataType::Humidity, humidity));
+            metrics.push((Sensor::Scd41, DataType::Co2, self.scd41_co2));
+            metrics.push((Sensor::Scd41, DataType::Temperature, self.scd41_temperature));
+        }
+        messages.extend(metrics.into_iter().map(|(sensor, data_type, value)| {
+            let payload = text(&data_type, value).into_bytes();
+            (SensorTopic::new(entity, sensor, data_type).mqtt(), payload)
+        }));
+        messages
+    }
+}
+
+/// A virtual node of an entity.
+pub struct Node {
+    pub entity: String,
+    rng: StdRng,
+    offsets: HashMap<DataType, f64>, // The deviation of the entity from the mean.
+    stuck: HashMap<(Sensor, DataType), (f64, u64)>, // (value, remaining cycles)
+    silent: u64, // The remaining cycles of the dropout.
+}
+
+impl Node {
+    pub fn new(entity: &str, seed: u64, simulation: &Simulation) -> Node {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let offsets = simulation.cycles.iter()
+            .map(|cycle| (cycle.data_type.clone(), cycle.amplitude.abs() * rng.gen_range(-0.2..0.2)))
+            .collect();
+        Node { entity: entity.to_string(), rng, offsets, stuck: HashMap::new(), silent: 0 }
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.rng.gen_range(0.0..1.0) < probability
+    }
+
+    fn metric(&mut self, simulation: &Simulation, sensor: Sensor, data_type: DataType, phase: f64, faults: &mut Vec<String>) -> f64 {
+        let key = (sensor.clone(), data_type.clone());
+        if let Some((value, remaining)) = self.stuck.get_mut(&key) {
+            let value = *value;
+            *remaining -= 1;
+            if *remaining == 0 {
+                self.stuck.remove(&key);
+            }
+            return value;
+        }
+        let cycle = simulation.cycle(&data_type);
+        let mut value = cycle.value(phase, simulation.noise, &mut self.rng) + self.offsets.get(&data_type).copied().unwrap_or(0.0);
+        if (&sensor, &data_type) == (&Sensor::Scd41, &DataType::Temperature) {
+            value += SCD41_TEMPERATURE_OFFSET;
+        }
+        if self.chance(simulation.faults.spike) {
+            let sign = if self.rng.gen_range(0.0..1.0) < 0.5 { -1.0 } else { 1.0 };
+            value += sign * cycle.amplitude.abs().max(cycle.noise) * self.rng.gen_range(3.0..6.0);
+            faults.push(format!("spike of {} {}", sensor, data_type));
+        }
+        if data_type == DataType::Humidity {
+            value = value.clamp(0.0, 100.0);
+        }
+        if self.chance(simulation.faults.stuck) {
+            self.stuck.insert(key, (value, STUCK_CYCLES - 1));
+            faults.push(format!("{} {} is stuck for {} cycles", sensor, data_type, STUCK_CYCLES));
+        }
+        value
+    }
+
+    /// The readings of the next cycle, or None while the node drops out. The injected faults are described in `faults`.
+    pub fn read(&mut self, simulation: &Simulation, phase: f64, faults: &mut Vec<String>) -> Option<Readings> {
+        if self.silent > 0 {
+            self.silent -= 1;
+            return None;
+        }
+        if self.chance(simulation.faults.dropout) {
+            self.silent = self.rng.gen_range(1..=MAX_DROPOUT_CYCLES) - 1;
+            faults.push(format!("dropout for {} cycles", self.silent + 1));
+            return None;
+        }
+        let missing_humidity = self.chance(simulation.faults.missing_humidity);
+        if missing_humidity {
+            faults.push("missing SCD41 humidity".to_string());
+        }
+        let humidity = self.metric(simulation, Sensor::Scd41, DataType::Humidity, phase, faults);
+        Some(Readings {
+            bmp180_temperature: self.metric(simulation, Sensor::Bmp180, DataType::Temperature, phase, faults),
+            bmp180_air_pressure: self.metric(simulation, Sensor::Bmp180, DataType::AirPressure, phase, faults),
+            scd41_humidity: if missing_humidity { None } else { Some(humidity) },
+            scd41_co2: self.metric(simulation, Sensor::Scd41, DataType::Co2, phase, faults),
+            scd41_temperature: self.metric(simulation, Sensor::Scd41, DataType::Temperature, phase, faults),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::Payload;
+
+    fn simulation(faults: Faults) -> Simulation {
+        Simulation {
+            entities: vec!["sim001".to_string()],
+            interval: Duration::from_secs(15),
+            day: 86400,
+            document: Document::Json,
+            qos: 0,
+            count: None,
+            cycles: Cycle::defaults(),
+            noise: 0.0,
+            faults,
+            seed: Some(1),
+        }
+    }
+
+    #[test]
+    fn test_messages_of_mqtt_pub() {
+        let readings = Readings { bmp180_temperature: 24.5, bmp180_air_pressure: 101325.25, scd41_humidity: Some(45.123), scd41_co2: 812.4, scd41_temperature: 25.3 };
+        let messages = readings.messages("sim001", &Document::MessagePack);
+        let topics: Vec<&str> = messages.iter().map(|(topic, _)| topic.as_str()).collect();
+        assert_eq!(topics, vec![
+            "i483/sensors/sim001/msgpack",
+            "i483/sensors/sim001/BMP180/temperature",
+            "i483/sensors/sim001/BMP180/air_pressure",
+            "i483/sensors/sim001/SCD41/humidity",
+            "i483/sensors/sim001/SCD41/co2",
+            "i483/sensors/sim001/SCD41/temperature",
+        ]);
+        assert_eq!(Payload::decode(&messages[0].0, &messages[0].1), Payload::Document(readings.document()));
+        assert_eq!(messages[3].1, b"45.12");
+        assert_eq!(messages[4].1, b"812");
+
+        let readings = Readings { scd41_humidity: None, ..readings };
+        let messages = readings.messages("sim001", &Document::Json);
+        assert_eq!(messages.len(), 3);
+        assert_eq!(Payload::decode(&messages[0].0, &messages[0].1), Payload::Document(readings.document()));
+        assert!(readings.document()["scd41"]["humidity"].is_null());
+    }
+
+    #[test]
+    fn test_daily_cycle() {
+        let simulation = simulation(Faults::default());
+        let mut node = Node::new("sim001", 1, &simulation);
+        node.offsets.clear();
+        let mut faults = Vec::new();
+        let afternoon = node.read(&simulation, 0.625, &mut faults).unwrap();
+        assert_eq!(afternoon.bmp180_temperature, 27.0);
+        assert_eq!(afternoon.scd41_temperature, 27.0 + SCD41_TEMPERATURE_OFFSET);
+        assert_eq!(afternoon.scd41_humidity, Some(37.0));
+        let night = node.read(&simulation, 0.125, &mut faults).unwrap();
+        assert_eq!(night.scd41_co2, 450.0);
+        assert!(faults.is_empty());
+        assert_eq!(simulation.phase(DateTime::parse_from_rfc3339("2026-10-18T15:00:00Z").unwrap().with_timezone(&Utc)), 0.625);
+    }
+
+    #[test]
+    fn test_faults() {
+        let mut faults = Vec::new();
+        let simulation = simulation(Faults { dropout: 1.0, ..Faults::default() });
+        let mut node = Node::new("sim001", 1, &simulation);
+        assert_eq!(node.read(&simulation, 0.5, &mut faults), None);
+        assert!(faults[0].starts_with("dropout"));
+
+        let simulation = Simulation { noise: 1.0, ..self::simulation(Faults { stuck: 1.0, missing_humidity: 1.0, ..Faults::default() }) };
+        let mut node = Node::new("sim001", 1, &simulation);
+        let first = node.read(&simulation, 0.5, &mut faults).unwrap();
+        let second = node.read(&simulation, 0.6, &mut faults).unwrap();
+        assert_eq!((first.scd41_humidity, second.scd41_humidity), (None, None));
+        assert_eq!(first.scd41_co2, second.scd41_co2);
+        assert_eq!(first.bmp180_air_pressure, second.bmp180_air_pressure);
+
+        let simulation = self::simulation(Faults { spike: 1.0, ..Faults::default() });
+        let mut node = Node::new("sim001", 1, &simulation);
+        node.offsets.clear();
+        let spiked = node.read(&simulation, 0.625, &mut faults).unwrap();
+        assert!((spiked.scd41_co2 - 950.0).abs() >= 750.0);
+    }
+}
@@ -0,0 +1,6 @@
+/*
+    The shared model of the sensor topics which are used by both the MQTT subscriber and the Kafka publisher.
+*/
+pub mod topic;
+
+pub use topic::{DataType, Sensor, SensorTopic};
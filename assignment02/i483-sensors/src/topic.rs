@@ -0,0 +1,275 @@
+/*
+    The topic model of the course: i483/sensors/[ENTITY]/[SENSOR]/[DATA_TYPE]
+    * ENTITY: Student ID or Team ID
+    * SENSOR: BMP180 or SCD41 ...
+    * DATA_TYPE: temperature, humidity, co2, air_pressure, illumination, ambient_illumination
+
+    The MQTT topics are separated by `/`, and the topics on Kafka are flattened with `-`
+    (e.g. i483-sensors-s2420010-SCD41-co2).
+    The processed streams are named with a `_` suffix of the sensor or the data type
+    (e.g. i483-sensors-s2420010-SCD41_avg-co2), and they are not sensor topics.
+*/
+use std::fmt::{self, Display, Formatter};
+
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Sensor {
+    Bmp180,
+    Scd41,
+    Bh1750,
+    Dps310,
+    Other(String),
+}
+
+impl Sensor {
+    pub fn parse(sensor: &str) -> Sensor {
+        match sensor.to_ascii_uppercase().as_str() {
+            "BMP180" => Sensor::Bmp180,
+            "SCD41" => Sensor::Scd41,
+            "BH1750" => Sensor::Bh1750,
+            "DPS310" => Sensor::Dps310,
+            _ => Sensor::Other(sensor.to_string()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Sensor::Bmp180 => "BMP180",
+            Sensor::Scd41 => "SCD41",
+            Sensor::Bh1750 => "BH1750",
+            Sensor::Dps310 => "DPS310",
+            Sensor::Other(name) => name,
+        }
+    }
+
+    /// The data types which are published for the sensor.
+    pub fn data_types(&self) -> Vec<DataType> {
+        match self {
+            Sensor::Bmp180 | Sensor::Dps310 => vec![DataType::Temperature, DataType::AirPressure],
+            Sensor::Scd41 => vec![DataType::Co2, DataType::Temperature, DataType::Humidity],
+            Sensor::Bh1750 => vec![DataType::Illumination],
+            Sensor::Other(_) => vec![],
+        }
+    }
+}
+
+impl Display for Sensor {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum DataType {
+    Temperature,
+    Humidity,
+    Co2,
+    AirPressure,
+    Illumination,
+    AmbientIllumination,
+    Other(String),
+}
+
+impl DataType {
+    pub fn parse(data_type: &str) -> DataType {
+        match data_type.to_ascii_lowercase().as_str() {
+            "temperature" => DataType::Temperature,
+            "humidity" => DataType::Humidity,
+            "co2" => DataType::Co2,
+            "air_pressure" => DataType::AirPressure,
+            "illumination" => DataType::Illumination,
+            "ambient_illumination" => DataType::AmbientIllumination,
+            _ => DataType::Other(data_type.to_string()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            DataType::Temperature => "temperature",
+            DataType::Humidity => "humidity",
+            DataType::Co2 => "co2",
+            DataType::AirPressure => "air_pressure",
+            DataType::Illumination => "illumination",
+            DataType::AmbientIllumination => "ambient_illumination",
+            DataType::Other(name) => name,
+        }
+    }
+
+    /// The unit of the values which are published by the nodes. The air pressure is in Pa, as the BMP180 driver returns it.
+    pub fn unit(&self) -> Option<&'static str> {
+        match self {
+            DataType::Temperature => Some("°C"),
+            DataType::Humidity => Some("%RH"),
+            DataType::Co2 => Some("ppm"),
+            DataType::AirPressure => Some("Pa"),
+            DataType::Illumination | DataType::AmbientIllumination => Some("lx"),
+            DataType::Other(_) => None,
+        }
+    }
+}
+
+impl Display for DataType {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SensorTopic {
+    pub entity: String,
+    pub sensor: Sensor,
+    pub data_type: DataType,
+}
+
+impl SensorTopic {
+    pub fn new(entity: &str, sensor: Sensor, data_type: DataType) -> SensorTopic {
+        SensorTopic {
+            entity: entity.to_string(),
+            sensor,
+            data_type,
+        }
+    }
+
+    /// Parses both `i483/sensors/E/S/T` and the flattened `i483-sensors-E-S-T`.
+    /// The derived topics of the processed streams are rejected.
+    pub fn parse(topic: &str) -> Option<SensorTopic> {
+        let separator = if topic.contains('/') { '/' } else { '-' };
+        let parts: Vec<&str> = topic.split(separator).collect();
+        if parts.len() < 5 || parts[0] != "i483" || parts[1] != "sensors" {
+            return None;
+        }
+        let entity = parts[2..parts.len() - 2].join(&separator.to_string());
+        let sensor = parts[parts.len() - 2];
+        let data_type = parts[parts.len() - 1];
+        if entity.is_empty() || sensor.is_empty() || data_type.is_empty() || is_derived(&parts[2..parts.len() - 2], sensor, data_type) {
+            return None;
+        }
+        Some(SensorTopic::new(&entity, Sensor::parse(sensor), DataType::parse(data_type)))
+    }
+
+    pub fn mqtt(&self) -> String {
+        format!("i483/sensors/{}/{}/{}", self.entity, self.sensor, self.data_type)
+    }
+
+    pub fn kafka(&self) -> String {
+        format!("i483-sensors-{}-{}-{}", self.entity, self.sensor, self.data_type)
+    }
+
+    /// The flattened topic of a processed stream,
+    /// e.g. `derived("_avg", "")` is `i483-sensors-E-S_avg-T`.
+    pub fn derived(&self, sensor_suffix: &str, data_type_suffix: &str) -> String {
+        format!("i483-sensors-{}-{}{}-{}{}", self.entity, self.sensor, sensor_suffix, self.data_type, data_type_suffix)
+    }
+
+    /// The key which identifies a sensor of an entity, e.g. `s2420010-SCD41`.
+    pub fn key(&self) -> String {
+        format!("{}-{}", self.entity, self.sensor)
+    }
+
+    pub fn unit(&self) -> Option<&'static str> {
+        self.data_type.unit()
+    }
+
+    /// The measurement range of the sensor from its datasheet (in the unit of `unit()`).
+    pub fn valid_range(&self) -> Option<(f64, f64)> {
+        match (&self.sensor, &self.data_type) {
+            (Sensor::Bmp180, DataType::Temperature) => Some((-40.0, 85.0)),
+            (Sensor::Bmp180, DataType::AirPressure) => Some((30000.0, 110000.0)),
+            (Sensor::Dps310, DataType::Temperature) => Some((-40.0, 85.0)),
+            (Sensor::Dps310, DataType::AirPressure) => Some((30000.0, 120000.0)),
+            (Sensor::Scd41, DataType::Co2) => Some((400.0, 5000.0)),
+            (Sensor::Scd41, DataType::Temperature) => Some((-10.0, 60.0)),
+            (Sensor::Scd41, DataType::Humidity) => Some((0.0, 100.0)),
+            (Sensor::Bh1750, DataType::Illumination) => Some((0.0, 65535.0)),
+            (_, DataType::Humidity) => Some((0.0, 100.0)),
+            _ => None,
+        }
+    }
+
+    /// All the topics of the known sensors of an entity.
+    pub fn known_topics(entity: &str) -> Vec<SensorTopic> {
+        [Sensor::Bmp180, Sensor::Scd41, Sensor::Bh1750, Sensor::Dps310].into_iter()
+            .flat_map(|sensor| sensor.data_types().into_iter().map(move |data_type| SensorTopic::new(entity, sensor.clone(), data_type)))
+            .collect()
+    }
+}
+
+// The suffixes of `derived()`: the sensor of the parts has a `_` suffix (`SCD41_avg-co2`), the data type has one (`co2_avg`),
+// or the suffix with `-` moves the sensor into the entity (`SCD41-co2_threshold-crossed`).
+fn is_derived(entity: &[&str], sensor: &str, data_type: &str) -> bool {
+    let is_known_sensor = |part: &str| !matches!(Sensor::parse(part.split('_').next().unwrap_or(part)), Sensor::Other(_));
+    let is_suffixed_data_type = matches!(DataType::parse(data_type), DataType::Other(_)) && data_type.char_indices()
+        .any(|(index, c)| c == '_' && !matches!(DataType::parse(&data_type[..index]), DataType::Other(_)));
+    sensor.contains('_') || is_suffixed_data_type || entity.iter().any(|part| is_known_sensor(part))
+}
+
+impl Display for SensorTopic {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.mqtt())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mqtt_topic() {
+        let topic = SensorTopic::parse("i483/sensors/s2420010/SCD41/co2").unwrap();
+        assert_eq!(topic.entity, "s2420010");
+        assert_eq!(topic.sensor, Sensor::Scd41);
+        assert_eq!(topic.data_type, DataType::Co2);
+        assert_eq!(topic.kafka(), "i483-sensors-s2420010-SCD41-co2");
+    }
+
+    #[test]
+    fn test_parse_kafka_topic() {
+        let topic = SensorTopic::parse("i483-sensors-s2420010-BMP180-air_pressure").unwrap();
+        assert_eq!(topic.sensor, Sensor::Bmp180);
+        assert_eq!(topic.data_type, DataType::AirPressure);
+        assert_eq!(topic.mqtt(), "i483/sensors/s2420010/BMP180/air_pressure");
+        assert_eq!(topic.unit(), Some("Pa"));
+        assert_eq!(topic.valid_range(), Some((30000.0, 110000.0)));
+    }
+
+    #[test]
+    fn test_parse_entity_with_separator() {
+        let topic = SensorTopic::parse("i483-sensors-team-1-SCD41-humidity").unwrap();
+        assert_eq!(topic.entity, "team-1");
+        assert_eq!(topic.key(), "team-1-SCD41");
+    }
+
+    #[test]
+    fn test_parse_invalid_topic() {
+        assert_eq!(SensorTopic::parse("i483/sensors/s2420010/json"), None);
+        assert_eq!(SensorTopic::parse("other-topic"), None);
+        assert_eq!(SensorTopic::parse("i483/sensors//SCD41/co2"), None);
+    }
+
+    #[test]
+    fn test_parse_derived_topic() {
+        let topic = SensorTopic::parse("i483-sensors-s2420010-SCD41-co2").unwrap();
+        for derived in [topic.derived("_avg", ""), topic.derived("", "_threshold-crossed"), topic.derived("", "_avg"), topic.derived("_avg", "-debug")] {
+            assert_eq!(SensorTopic::parse(&derived), None, "{}", derived);
+        }
+        assert_eq!(SensorTopic::parse("i483-sensors-s2420010-SCD41_avg-co2"), None);
+        assert_eq!(SensorTopic::parse("i483-sensors-s2420010-SCD41-co2_threshold-crossed"), None);
+        assert!(SensorTopic::parse("i483-sensors-s2420010-BMP180-air_pressure").is_some());
+        assert!(SensorTopic::parse("i483-sensors-s2420010-BH1750-ambient_illumination").is_some());
+        assert_eq!(SensorTopic::parse("i483-sensors-s2420010-BH1750-ambient_illumination_avg"), None);
+    }
+
+    #[test]
+    fn test_derived_topic() {
+        let topic = SensorTopic::parse("i483-sensors-s2420010-SCD41-temperature").unwrap();
+        assert_eq!(topic.derived("_avg", ""), "i483-sensors-s2420010-SCD41_avg-temperature");
+        assert_eq!(topic.derived("", "_threshold-crossed"), "i483-sensors-s2420010-SCD41-temperature_threshold-crossed");
+    }
+
+    #[test]
+    fn test_known_topics() {
+        let topics = SensorTopic::known_topics("s2420010");
+        assert!(topics.contains(&SensorTopic::new("s2420010", Sensor::Scd41, DataType::Co2)));
+        assert!(topics.iter().all(|topic| topic.unit().is_some()));
+    }
+}